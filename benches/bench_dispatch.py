@@ -0,0 +1,102 @@
+"""Compare the cost of calling dispatchers against `functools.singledispatch`.
+
+Run with `python benches/bench_dispatch.py` once the extension is installed
+(e.g. with `maturin develop --release`, or with `--no-default-features` added
+to time dispatchers implementing vectorcall).
+"""
+
+import collections.abc
+import functools
+import timeit
+
+import singledispatch_native
+
+
+def _define(singledispatch):
+    @singledispatch
+    def f(o, *args, **kwargs):
+        return "object"
+
+    @f.register(int)
+    def _f_int(o, *args, **kwargs):
+        return "int"
+
+    @f.register(collections.abc.Sequence)
+    def _f_sequence(o, *args, **kwargs):
+        return "sequence"
+
+    return f
+
+
+def _direct(o, *args, **kwargs):
+    return "int"
+
+
+CASES = {
+    "f(1)": lambda f: lambda: f(1),
+    "f(True)": lambda f: lambda: f(True),
+    "f([])": lambda f: lambda: f([]),
+    "f(1, 2, 3)": lambda f: lambda: f(1, 2, 3),
+    "f(1, key=2)": lambda f: lambda: f(1, key=2),
+}
+
+
+ABCS = [
+    collections.abc.MutableSequence,
+    collections.abc.Sequence,
+    collections.abc.Reversible,
+    collections.abc.Collection,
+    collections.abc.Iterable,
+    collections.abc.Container,
+    collections.abc.Sized,
+    collections.abc.Mapping,
+    collections.abc.Set,
+    collections.abc.Hashable,
+]
+
+
+def _define_large(singledispatch, size: int):
+    @singledispatch
+    def f(o):
+        return "object"
+
+    for i in range(size - len(ABCS)):
+        f.register(type(f"Registered{i}", (), {}), lambda o: "registered")
+    for abc in ABCS:
+        f.register(abc, lambda o: "abc")
+    return f
+
+
+def bench_misses(size: int = 500, number: int = 200) -> None:
+    """Time the first dispatch of new classes, which isn't cached yet."""
+    print(f"\nfirst dispatch of a new list subclass with {size} handlers")
+    for name, singledispatch in {
+        "functools": functools.singledispatch,
+        "native": singledispatch_native.singledispatch,
+    }.items():
+        f = _define_large(singledispatch, size)
+        classes = iter([type(f"C{i}", (list,), {}) for i in range(number * 5)])
+        best = min(timeit.repeat(lambda: f.dispatch(next(classes)), number=number, repeat=5))
+        print(f"{name:<14}{best / number * 1e6:>9.1f} us")
+
+
+def main(number: int = 1_000_000) -> None:
+    implementations = {
+        "direct": _direct,
+        "functools": _define(functools.singledispatch),
+        "native": _define(singledispatch_native.singledispatch),
+    }
+    print(f"{'case':<14}" + "".join(f"{name:>12}" for name in implementations))
+    for case, make_call in CASES.items():
+        timings = []
+        for f in implementations.values():
+            call = make_call(f)
+            call()  # Warm up the dispatch cache.
+            best = min(timeit.repeat(call, number=number, repeat=5))
+            timings.append(best / number * 1e9)
+        print(f"{case:<14}" + "".join(f"{t:>9.1f} ns" for t in timings))
+    bench_misses()
+
+
+if __name__ == "__main__":
+    main()
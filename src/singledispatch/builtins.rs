@@ -1,34 +1,76 @@
+use pyo3::ffi;
 use pyo3::prelude::*;
 use pyo3::sync::GILOnceCell;
-use pyo3::types::{PyBool, PyTuple};
-use pyo3::{IntoPyObjectExt, PyObject, Python};
+use pyo3::types::PyType;
+use pyo3::{intern, IntoPyObjectExt, PyObject, Python};
+use std::ffi::{c_int, c_void};
 
 pub struct Builtins {
     pub object_type: PyObject,
-    issubclass_func: PyObject,
+    /// The descriptors of `type.__mro__` and `type.__bases__`, which read the
+    /// MRO and bases straight from a type object (the limited API has no access
+    /// to `tp_mro` and `tp_bases`).
+    pub type_mro: TypeDescriptor,
+    pub type_bases: TypeDescriptor,
+    /// Whether `PyType_GetSlot` accepts static types, which it does from Python
+    /// 3.10 on.
+    static_type_slots: bool,
+}
+
+/// A descriptor of `type` along with its `tp_descr_get` slot, through which it
+/// is read without calling its `__get__` method.
+pub struct TypeDescriptor {
+    pub descriptor: PyObject,
+    descr_get: Option<ffi::descrgetfunc>,
+}
+
+impl TypeDescriptor {
+    /// The value of the descriptor for the type object `cls`, or `None` if the
+    /// slot of the descriptor can't be read on this version of Python.
+    pub fn get<'py>(&self, cls: &Bound<'py, PyType>) -> Option<PyResult<Bound<'py, PyAny>>> {
+        let descr_get = self.descr_get?;
+        let metaclass = cls.get_type();
+        Some(unsafe {
+            Bound::from_owned_ptr_or_err(
+                cls.py(),
+                descr_get(self.descriptor.as_ptr(), cls.as_ptr(), metaclass.as_ptr()),
+            )
+        })
+    }
 }
 
 static PY_BUILTINS: GILOnceCell<Builtins> = GILOnceCell::new();
 
 impl Builtins {
-    fn new(py: Python) -> Self {
-        let builtins_module = py.import("builtins").unwrap();
-        Builtins {
-            object_type: builtins_module
-                .getattr("object")
-                .unwrap()
-                .into_py_any(py)
-                .unwrap(),
-            issubclass_func: builtins_module
-                .getattr("issubclass")
-                .unwrap()
-                .into_py_any(py)
-                .unwrap(),
-        }
+    fn new(py: Python) -> PyResult<Self> {
+        let builtins_module = py.import("builtins")?;
+        let type_dict = py.get_type::<PyType>().getattr("__dict__")?;
+        let static_type_slots = py.version_info() >= (3, 10);
+        let type_descriptor = |name: &str| -> PyResult<TypeDescriptor> {
+            let descriptor = type_dict.get_item(name)?;
+            let descr_get = type_slot(
+                static_type_slots,
+                &descriptor.get_type(),
+                ffi::Py_tp_descr_get,
+            )
+            .map(|function| unsafe {
+                std::mem::transmute::<*mut c_void, ffi::descrgetfunc>(function)
+            });
+            Ok(TypeDescriptor {
+                descriptor: descriptor.unbind(),
+                descr_get,
+            })
+        };
+        Ok(Builtins {
+            object_type: builtins_module.getattr("object")?.into_py_any(py)?,
+            type_mro: type_descriptor("__mro__")?,
+            type_bases: type_descriptor("__bases__")?,
+            static_type_slots,
+        })
     }
 
-    pub fn cached(py: Python) -> &Self {
-        PY_BUILTINS.get_or_init(py, || Builtins::new(py))
+    pub fn cached(py: Python<'_>) -> PyResult<&Self> {
+        PY_BUILTINS.get_or_try_init(py, || Builtins::new(py))
     }
 
     pub fn issubclass(
@@ -37,10 +79,48 @@ impl Builtins {
         cls: &Bound<'_, PyAny>,
         typ: &Bound<'_, PyAny>,
     ) -> PyResult<bool> {
-        let args = PyTuple::new(py, [cls, typ]);
-        match self.issubclass_func.call1(py, args?) {
-            Ok(result) => Ok(result.downcast_bound::<PyBool>(py)?.is_true()),
-            Err(e) => Err(e),
+        // `PyTypeMethods::is_subclass` only accepts types, but `issubclass` also
+        // supports class-like objects.
+        match unsafe { ffi::PyObject_IsSubclass(cls.as_ptr(), typ.as_ptr()) } {
+            -1 => Err(PyErr::fetch(py)),
+            result => Ok(result == 1),
         }
     }
+
+    /// The function in the slot `slot` of `typ`, or `None` if the slot is empty
+    /// or can't be read on this version of Python.
+    pub fn type_slot(&self, typ: &Bound<'_, PyType>, slot: c_int) -> Option<*mut c_void> {
+        type_slot(self.static_type_slots, typ, slot)
+    }
+
+    /// Whether the instances of `typ` report `typ` as their `__class__`: their
+    /// attributes are looked up as usual, and no class in the MRO of `typ` other
+    /// than `object` defines `__class__`, as proxies and mocks do.
+    pub fn reports_own_class(&self, typ: &Bound<'_, PyType>) -> PyResult<bool> {
+        let py = typ.py();
+        let generic_getattr = ffi::PyObject_GenericGetAttr as *mut c_void;
+        if self.type_slot(typ, ffi::Py_tp_getattro) != Some(generic_getattr) {
+            return Ok(false);
+        }
+        for base in typ.mro().iter() {
+            if !base.is(&self.object_type)
+                && base
+                    .getattr(intern!(py, "__dict__"))?
+                    .contains(intern!(py, "__class__"))?
+            {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+fn type_slot(static_type_slots: bool, typ: &Bound<'_, PyType>, slot: c_int) -> Option<*mut c_void> {
+    let typ = typ.as_type_ptr();
+    let is_heap_type = unsafe { ffi::PyType_GetFlags(typ) } & ffi::Py_TPFLAGS_HEAPTYPE != 0;
+    if !is_heap_type && !static_type_slots {
+        return None;
+    }
+    let function = unsafe { ffi::PyType_GetSlot(typ, slot) };
+    (!function.is_null()).then_some(function)
 }
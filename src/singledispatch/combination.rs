@@ -0,0 +1,220 @@
+use crate::singledispatch::typing::TypingModule;
+use pyo3::exceptions::PyTypeError;
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyTuple};
+use pyo3::{
+    pyclass, pymethods, Bound, IntoPyObjectExt, Py, PyAny, PyObject, PyResult, PyTraverseError,
+    PyVisit, Python,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MethodQualifier {
+    Primary,
+    /// A primary method which only applies to the exact class it is registered
+    /// for, and not to its subclasses.
+    Exact,
+    Before,
+    After,
+    Around,
+}
+
+// The wrappers below are immutable once created, so like tuples they only
+// support traversal; reference cycles through them are broken by clearing the
+// dispatcher which caches them.
+
+/// The effective method for a class which has applicable `before` or `after`
+/// methods: before methods run most specific first, then the primary method,
+/// then after methods least specific first. Only the primary method's return
+/// value is returned.
+#[pyclass]
+struct MethodCombination {
+    before: Vec<PyObject>,
+    primary: PyObject,
+    after: Vec<PyObject>,
+}
+
+#[pymethods]
+impl MethodCombination {
+    fn __traverse__(&self, visit: PyVisit<'_>) -> Result<(), PyTraverseError> {
+        for method in self.before.iter().chain(self.after.iter()) {
+            visit.call(method)?;
+        }
+        visit.call(&self.primary)
+    }
+
+    #[pyo3(signature = (*args, **kwargs))]
+    fn __call__(
+        &self,
+        py: Python<'_>,
+        args: &Bound<'_, PyTuple>,
+        kwargs: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<Py<PyAny>> {
+        for before in self.before.iter() {
+            before.call(py, args, kwargs)?;
+        }
+        let result = self.primary.call(py, args, kwargs)?;
+        for after in self.after.iter() {
+            after.call(py, args, kwargs)?;
+        }
+        Ok(result)
+    }
+}
+
+/// An `around` method wrapping the rest of the effective method. The around
+/// method is called with the next method in the chain as its first argument
+/// and decides whether (and how) to proceed.
+#[pyclass]
+struct AroundMethod {
+    around: PyObject,
+    next: PyObject,
+}
+
+#[pymethods]
+impl AroundMethod {
+    fn __traverse__(&self, visit: PyVisit<'_>) -> Result<(), PyTraverseError> {
+        visit.call(&self.around)?;
+        visit.call(&self.next)
+    }
+
+    #[pyo3(signature = (*args, **kwargs))]
+    fn __call__(
+        &self,
+        py: Python<'_>,
+        args: &Bound<'_, PyTuple>,
+        kwargs: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<Py<PyAny>> {
+        let mut all_args = Vec::with_capacity(1 + args.len());
+        all_args.push(self.next.bind(py).clone());
+        all_args.extend(args);
+        self.around.call(py, PyTuple::new(py, all_args)?, kwargs)
+    }
+}
+
+/// A handler registered for a parameterized generic (such as `list[int]`) on a
+/// dispatcher which validates element types at call time.
+#[pyclass]
+struct ValidatingMethod {
+    annotation: PyObject,
+    method: PyObject,
+}
+
+#[pymethods]
+impl ValidatingMethod {
+    fn __traverse__(&self, visit: PyVisit<'_>) -> Result<(), PyTraverseError> {
+        visit.call(&self.annotation)?;
+        visit.call(&self.method)
+    }
+
+    #[pyo3(signature = (obj, /, *args, **kwargs))]
+    fn __call__(
+        &self,
+        py: Python<'_>,
+        obj: Bound<'_, PyAny>,
+        args: &Bound<'_, PyTuple>,
+        kwargs: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<Py<PyAny>> {
+        let annotation = self.annotation.bind(py);
+        if !TypingModule::cached(py)?.is_instance(py, &obj, annotation)? {
+            return Err(PyTypeError::new_err(format!(
+                "{} does not match registered type {annotation}",
+                obj.repr()?
+            )));
+        }
+
+        let mut all_args = Vec::with_capacity(1 + args.len());
+        all_args.push(obj);
+        all_args.extend(args);
+        self.method.call(py, PyTuple::new(py, all_args)?, kwargs)
+    }
+}
+
+/// The handlers applicable to a class on a dispatcher with `NotImplemented`
+/// fall-through, in MRO order. Each handler is tried in turn until one returns
+/// something other than `NotImplemented`.
+#[pyclass]
+struct FallThroughMethod {
+    handlers: Vec<PyObject>,
+}
+
+#[pymethods]
+impl FallThroughMethod {
+    fn __traverse__(&self, visit: PyVisit<'_>) -> Result<(), PyTraverseError> {
+        for handler in self.handlers.iter() {
+            visit.call(handler)?;
+        }
+        Ok(())
+    }
+
+    #[pyo3(signature = (*args, **kwargs))]
+    fn __call__(
+        &self,
+        py: Python<'_>,
+        args: &Bound<'_, PyTuple>,
+        kwargs: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<Py<PyAny>> {
+        let not_implemented = py.NotImplemented();
+        for handler in self.handlers.iter() {
+            let result = handler.call(py, args, kwargs)?;
+            if !result.is(&not_implemented) {
+                return Ok(result);
+            }
+        }
+        let tried = self
+            .handlers
+            .iter()
+            .map(|handler| Ok(handler.bind(py).repr()?.to_string()))
+            .collect::<PyResult<Vec<_>>>()?;
+        let cls = match args.get_item(0) {
+            Ok(obj) => obj.get_type().to_string(),
+            Err(_) => "no arguments".to_string(),
+        };
+        Err(PyTypeError::new_err(format!(
+            "All dispatch functions for {cls} returned NotImplemented: {}",
+            tried.join(", ")
+        )))
+    }
+}
+
+pub(crate) fn fall_through_method(py: Python, handlers: Vec<PyObject>) -> PyResult<PyObject> {
+    FallThroughMethod { handlers }.into_py_any(py)
+}
+
+pub(crate) fn validating_method(
+    py: Python,
+    annotation: PyObject,
+    method: PyObject,
+) -> PyResult<PyObject> {
+    ValidatingMethod { annotation, method }.into_py_any(py)
+}
+
+/// Combine the primary handler with any auxiliary methods into a single callable.
+///
+/// All auxiliary method lists are expected in MRO order (most specific first). If
+/// there are no auxiliary methods, the primary handler is returned as is.
+pub(crate) fn effective_method(
+    py: Python,
+    before: Vec<PyObject>,
+    primary: PyObject,
+    mut after: Vec<PyObject>,
+    around: Vec<PyObject>,
+) -> PyResult<PyObject> {
+    let mut method = if before.is_empty() && after.is_empty() {
+        primary
+    } else {
+        after.reverse();
+        MethodCombination {
+            before,
+            primary,
+            after,
+        }
+        .into_py_any(py)?
+    };
+    for around in around.into_iter().rev() {
+        method = AroundMethod {
+            around,
+            next: method,
+        }
+        .into_py_any(py)?;
+    }
+    Ok(method)
+}
@@ -1,57 +1,130 @@
-use crate::singledispatch::mro::{compose_mro, get_obj_mro};
-use crate::singledispatch::typeref::PyTypeReference;
+use crate::singledispatch::combination::{
+    effective_method, fall_through_method, validating_method, MethodQualifier,
+};
+use crate::singledispatch::entry_points::{read_entry_points, warn};
+use crate::singledispatch::lazy::{
+    display_handler, imported_module_count, is_handler, load_handler, mro_type_names,
+    resolve_type_name, LazyHandler,
+};
+use crate::singledispatch::mro::{compose_mro, get_known_subclasses, get_obj_mro, TypeInfo};
+use crate::singledispatch::typemap::TypeMap;
+use crate::singledispatch::typeref::{AddressHash, PyTypeReference};
 use crate::singledispatch::typing::TypingModule;
+#[cfg(not(feature = "abi3"))]
+use crate::singledispatch::vectorcall;
 use pyo3::basic::CompareOp;
-use pyo3::exceptions::{PyNotImplementedError, PyRuntimeError, PyTypeError};
+use pyo3::exceptions::{
+    PyAttributeError, PyKeyError, PyNotImplementedError, PyRuntimeError, PyTypeError, PyValueError,
+};
 use pyo3::prelude::*;
+use pyo3::sync::GILOnceCell;
 
 use crate::singledispatch::builtins::Builtins;
-use pyo3::types::{PyDict, PyTuple, PyType};
+use pyo3::types::{PyDict, PyList, PyString, PyTuple, PyType};
 use pyo3::{
-    intern, pyclass, pyfunction, pymethods, Bound, IntoPyObjectExt, Py, PyAny, PyObject, PyResult,
-    Python,
+    ffi, intern, pyclass, pyfunction, pymethods, Bound, IntoPyObjectExt, Py, PyAny, PyObject,
+    PyResult, PyTraverseError, PyVisit, Python,
 };
-use std::collections::HashMap;
-use std::sync::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+static GET_CACHE_TOKEN: GILOnceCell<PyObject> = GILOnceCell::new();
+static NEXT_DISPATCHER_ID: AtomicU64 = AtomicU64::new(0);
 
-fn get_abc_cache_token(py: Python) -> PyResult<Bound<'_, PyAny>> {
-    py.import(intern!(py, "abc"))?
-        .getattr(intern!(py, "get_cache_token"))?
+fn lock_state<T>(lock: &Mutex<T>) -> PyResult<MutexGuard<'_, T>> {
+    lock.lock()
+        .map_err(|e| PyRuntimeError::new_err(format!("Singledispatch mutex poisoned: {e}")))
+}
+
+fn get_abc_cache_token(py: Python<'_>) -> PyResult<Bound<'_, PyAny>> {
+    GET_CACHE_TOKEN
+        .import(py, "abc", "get_cache_token")?
         .call0()
 }
 
-fn valid_dispatch_types(py: Python, cls: &Bound<'_, PyAny>) -> PyResult<Vec<Py<PyType>>> {
+/// A type handlers can be registered for, along with the annotation it was
+/// registered with if that was a parameterized generic such as `list[int]`.
+struct DispatchType {
+    typ: Py<PyType>,
+    annotation: Option<PyObject>,
+    class_object: bool,
+}
+
+impl DispatchType {
+    fn key(&self, py: Python) -> PyTypeReference {
+        PyTypeReference::new(self.typ.clone_ref(py).into_any())
+    }
+
+    /// The key this type is shown with in `SingleDispatch.registry`.
+    fn registry_key(&self, py: Python) -> PyObject {
+        match &self.annotation {
+            Some(annotation) => annotation.clone_ref(py),
+            None => self.typ.clone_ref(py).into_any(),
+        }
+    }
+}
+
+/// Parameterized generics dispatch on their origin, e.g. `list[int]` dispatches
+/// on `list`, except for `type[X]` which dispatches on class objects which are
+/// subclasses of `X`.
+fn dispatch_type(py: Python, cls: &Bound<'_, PyAny>) -> PyResult<Option<DispatchType>> {
+    let typing_module = TypingModule::cached(py)?;
+    let cls = &typing_module.resolve_type_alias(py, cls)?;
     if let Ok(typ) = cls.downcast::<PyType>() {
-        Ok(Vec::from([typ.clone().unbind()]))
-    } else {
-        let typing_module = TypingModule::cached(py);
-        if let Ok(origin) = typing_module.get_origin(py, cls) {
-            if typing_module.is_union_type(py, origin.bind(py))? {
-                if let Ok(type_args) = typing_module.get_args(py, cls) {
-                    let py_tuple = type_args.bind(py);
-                    let mut dispatch_types = Vec::with_capacity(py_tuple.len());
-                    for (i, item) in py_tuple.iter().enumerate() {
-                        match item.downcast::<PyType>() {
-                            Ok(typ) => {
-                                dispatch_types.insert(i, typ.clone().unbind());
-                            }
-                            Err(_) => {
-                                return Err(PyTypeError::new_err(format!(
-                                    "Object {item} is not a valid type"
-                                )))
-                            }
-                        }
-                    }
-                    Ok(dispatch_types)
-                } else {
-                    Ok(Vec::new())
+        return Ok(Some(DispatchType {
+            typ: typ.clone().unbind(),
+            annotation: None,
+            class_object: false,
+        }));
+    }
+    let origin = typing_module.get_origin(py, cls)?;
+    match origin.downcast_bound::<PyType>(py) {
+        Ok(typ) if typ.is(py.get_type::<PyType>()) => {
+            let args = typing_module.get_args(py, cls)?;
+            let args = args.bind(py);
+            if args.len() != 1 {
+                return Ok(None);
+            }
+            match args.get_item(0)?.downcast::<PyType>() {
+                Ok(class_typ) => Ok(Some(DispatchType {
+                    typ: class_typ.clone().unbind(),
+                    annotation: Some(cls.clone().unbind()),
+                    class_object: true,
+                })),
+                Err(_) => Ok(None),
+            }
+        }
+        Ok(typ) => Ok(Some(DispatchType {
+            typ: typ.clone().unbind(),
+            annotation: Some(cls.clone().unbind()),
+            class_object: false,
+        })),
+        Err(_) => Ok(None),
+    }
+}
+
+fn valid_dispatch_types(py: Python, cls: &Bound<'_, PyAny>) -> PyResult<Vec<DispatchType>> {
+    let typing_module = TypingModule::cached(py)?;
+    let cls = &typing_module.resolve_type_alias(py, cls)?;
+    let origin = typing_module.get_origin(py, cls)?;
+    if typing_module.is_union_type(py, origin.bind(py))? {
+        let type_args = typing_module.get_args(py, cls)?;
+        let py_tuple = type_args.bind(py);
+        let mut dispatch_types = Vec::with_capacity(py_tuple.len());
+        for item in py_tuple.iter() {
+            match dispatch_type(py, &item)? {
+                Some(typ) => dispatch_types.push(typ),
+                None => {
+                    return Err(PyTypeError::new_err(format!(
+                        "Object {item} is not a valid type"
+                    )))
                 }
-            } else {
-                Ok(Vec::new())
             }
-        } else {
-            Ok(Vec::new())
         }
+        Ok(dispatch_types)
+    } else {
+        Ok(dispatch_type(py, cls)?.into_iter().collect())
     }
 }
 
@@ -63,124 +136,1717 @@ fn is_valid_dispatch_type(py: Python, cls: &Bound<'_, PyAny>) -> bool {
     }
 }
 
+/// Per-dispatcher options which are fixed when the dispatcher is created and
+/// shared with dispatchers derived from it.
+struct DispatchOptions {
+    validate_generics: bool,
+    fall_through: bool,
+    resolution: Resolution,
+    /// Custom linearization replacing `compose_mro`, called with the class and
+    /// the registered types and returning the candidate types in order.
+    mro: Option<PyObject>,
+}
+
+impl DispatchOptions {
+    fn clone_ref(&self, py: Python) -> Self {
+        DispatchOptions {
+            validate_generics: self.validate_generics,
+            fall_through: self.fall_through,
+            resolution: self.resolution,
+            mro: self.mro.as_ref().map(|mro| mro.clone_ref(py)),
+        }
+    }
+
+    fn traverse(&self, visit: &PyVisit<'_>) -> Result<(), PyTraverseError> {
+        visit.call(&self.mro)
+    }
+
+    /// Whether the candidates for a class are given in a fixed order of preference,
+    /// rather than being chosen by specificity.
+    fn is_linearized(&self) -> bool {
+        self.mro.is_some() || self.resolution == Resolution::Ordered
+    }
+
+    /// Order `types` by how closely they match `cls`, most specific first.
+    fn compose_mro<'a>(
+        &self,
+        py: Python,
+        cls: &Bound<'_, PyAny>,
+        types: impl Iterator<Item = (&'a PyTypeReference, &'a TypeInfo)>,
+    ) -> PyResult<Vec<PyTypeReference>> {
+        if self.resolution == Resolution::Ordered {
+            return ordered_candidates(py, cls, types.map(|(typ, _)| typ));
+        }
+        match &self.mro {
+            Some(mro) => {
+                let mut seen = HashSet::new();
+                let types: Vec<&PyObject> = types
+                    .map(|(typ, _)| typ)
+                    .filter(|typ| seen.insert(*typ))
+                    .map(|typ| typ.wrapped())
+                    .collect();
+                let types = PyList::new(py, types)?;
+                let mut candidates = Vec::new();
+                for typ in mro.bind(py).call1((cls, types))?.try_iter()? {
+                    candidates.push(PyTypeReference::new(typ?.unbind()));
+                }
+                Ok(candidates)
+            }
+            None => compose_mro(py, cls.clone(), types),
+        }
+    }
+}
+
+/// How the handler for a class is chosen among the registered types.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Resolution {
+    /// The most specific type in the (composed) MRO of the class.
+    Mro,
+    /// The first registered type the class is a subclass of, in registration
+    /// order, like the cases of a `match` statement.
+    Ordered,
+}
+
+impl Resolution {
+    fn parse(resolution: &str) -> PyResult<Self> {
+        match resolution {
+            "mro" => Ok(Resolution::Mro),
+            "ordered" => Ok(Resolution::Ordered),
+            _ => Err(PyValueError::new_err(format!(
+                "invalid resolution '{resolution}'. Expected 'mro' or 'ordered'."
+            ))),
+        }
+    }
+}
+
+/// The registered `types` which `cls` is a subclass of, in registration order.
+/// `object` always comes last, since it would otherwise match every class.
+fn ordered_candidates<'a>(
+    py: Python,
+    cls: &Bound<'_, PyAny>,
+    types: impl Iterator<Item = &'a PyTypeReference>,
+) -> PyResult<Vec<PyTypeReference>> {
+    let builtins = Builtins::cached(py)?;
+    let mut seen = HashSet::new();
+    let mut candidates = Vec::new();
+    let mut default = None;
+    for typ in types {
+        if !seen.insert(typ) {
+            continue;
+        }
+        if typ.wrapped().is(&builtins.object_type) {
+            default = Some(typ.clone_ref(py));
+        } else if builtins.issubclass(py, cls, typ.wrapped().bind(py))? {
+            candidates.push(typ.clone_ref(py));
+        }
+    }
+    candidates.extend(default);
+    Ok(candidates)
+}
+
+/// Outcome of matching a class against the types registered in a table.
+enum MroMatch {
+    Found(PyTypeReference),
+    Ambiguous(PyTypeReference, PyTypeReference),
+    NotFound,
+}
+
+fn match_mro(
+    py: Python,
+    table: &TypeMap,
+    cls: &Bound<'_, PyAny>,
+    options: &DispatchOptions,
+) -> PyResult<MroMatch> {
+    if options.is_linearized() {
+        // The order of the candidates is fully determined, so there is nothing
+        // to be ambiguous about.
+        let mro = options.compose_mro(py, cls, table.indexed())?;
+        return Ok(match mro.into_iter().find(|typ| table.contains_key(typ)) {
+            Some(m) => MroMatch::Found(m),
+            None => MroMatch::NotFound,
+        });
+    }
+    let cls_mro = get_obj_mro(cls)?;
+    let mro = compose_mro(py, cls.clone(), table.indexed())?;
+    let mut mro_match: Option<PyTypeReference> = None;
+    for typ in mro.iter() {
+        if let Some(m) = mro_match {
+            // If *m* is an implicit ABC but there is another unrelated, equally
+            // matching implicit ABC, refuse the temptation to guess.
+            if table.contains_key(typ)
+                && !cls_mro.contains(typ)
+                && !cls_mro.contains(&m)
+                && !Builtins::cached(py)?.issubclass(
+                    py,
+                    m.wrapped().bind(py),
+                    typ.wrapped().bind(py),
+                )?
+            {
+                return Ok(MroMatch::Ambiguous(m, typ.clone_ref(py)));
+            }
+            return Ok(MroMatch::Found(m));
+        }
+
+        if table.contains_key(typ) {
+            mro_match = Some(typ.clone_ref(py));
+        }
+    }
+    Ok(match mro_match {
+        Some(m) => MroMatch::Found(m),
+        None => MroMatch::NotFound,
+    })
+}
+
+/// Find the closest match for `cls` among the types registered in `table`.
+fn find_match(
+    py: Python,
+    table: &TypeMap,
+    cls: &Bound<'_, PyAny>,
+    options: &DispatchOptions,
+) -> PyResult<Option<PyTypeReference>> {
+    match match_mro(py, table, cls, options)? {
+        MroMatch::Found(m) => Ok(Some(m)),
+        MroMatch::Ambiguous(m, typ) => Err(PyRuntimeError::new_err(format!(
+            "Ambiguous dispatch: {m} or {typ}"
+        ))),
+        MroMatch::NotFound => Ok(None),
+    }
+}
+
+/// Observers are only notified of changes to primary handlers, including exact
+/// ones, but not of auxiliary methods.
+fn clone_observers(
+    py: Python,
+    observers: &[PyObject],
+    qualifier: MethodQualifier,
+) -> Vec<PyObject> {
+    if matches!(qualifier, MethodQualifier::Primary | MethodQualifier::Exact) {
+        observers.iter().map(|o| o.clone_ref(py)).collect()
+    } else {
+        Vec::new()
+    }
+}
+
+/// Call each observer with every changed registry key and handler. This must be
+/// called without the dispatcher's lock held, since observers may well inspect or
+/// modify the dispatcher.
+fn notify_observers(
+    py: Python,
+    observers: &[PyObject],
+    changes: &[(PyObject, PyObject)],
+) -> PyResult<()> {
+    for observer in observers {
+        for (cls, func) in changes {
+            observer.call1(py, (cls, func))?;
+        }
+    }
+    Ok(())
+}
+
+/// A class for which dispatch is ambiguous between two registered types.
+struct Ambiguity {
+    cls: PyTypeReference,
+    first: PyTypeReference,
+    second: PyTypeReference,
+}
+
+impl Ambiguity {
+    fn involves(&self, typ: &PyTypeReference) -> bool {
+        self.first == *typ || self.second == *typ
+    }
+}
+
+/// A registration by dotted type name (e.g. `"decimal.Decimal"`) which has not
+/// been resolved to a class yet.
+struct PendingRegistration {
+    name: String,
+    func: PyObject,
+    qualifier: MethodQualifier,
+    /// The entry point the registration was read from, if any, which warnings
+    /// about it name.
+    origin: Option<String>,
+}
+
+impl PendingRegistration {
+    fn clone_ref(&self, py: Python) -> Self {
+        PendingRegistration {
+            name: self.name.clone(),
+            func: self.func.clone_ref(py),
+            qualifier: self.qualifier,
+            origin: self.origin.clone(),
+        }
+    }
+}
+
+/// The outcome of looking up the type names of pending registrations: the
+/// classes they name, and the names which turned out not to name a class along
+/// with what they name instead.
+#[derive(Default)]
+struct Resolutions<'py> {
+    classes: Vec<(String, Bound<'py, PyType>)>,
+    invalid: Vec<(String, Bound<'py, PyAny>)>,
+}
+
+impl<'py> Resolutions<'py> {
+    /// Look up `names` for a lookup of `cls`, or with `cls` of `None`, only in the
+    /// modules which have already been imported. This may run arbitrary code,
+    /// e.g. a module `__getattr__`, so no lock may be held.
+    fn resolve(
+        py: Python<'py>,
+        names: Vec<String>,
+        cls: Option<&Bound<'py, PyAny>>,
+    ) -> PyResult<Self> {
+        let mro_names = match cls {
+            Some(cls) => mro_type_names(cls)?,
+            None => Vec::new(),
+        };
+        let mut resolutions = Resolutions::default();
+        for name in names {
+            if let Some(obj) = resolve_type_name(py, &name, &mro_names)? {
+                match obj.downcast_into::<PyType>() {
+                    Ok(typ) => resolutions.classes.push((name, typ)),
+                    Err(e) => resolutions.invalid.push((name, e.into_inner())),
+                }
+            }
+        }
+        Ok(resolutions)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.classes.is_empty() && self.invalid.is_empty()
+    }
+
+    /// Warn about the registrations which were dropped because their type name
+    /// doesn't name a class.
+    fn warn_dropped(&self, py: Python, dropped: &[PendingRegistration]) -> PyResult<()> {
+        for pending in dropped {
+            let name = &pending.name;
+            let obj = match self.invalid.iter().find(|(invalid, _)| invalid == name) {
+                Some((_, obj)) => obj,
+                None => continue,
+            };
+            let reason = format!(
+                "'{name}' does not name a class, but a '{}' object",
+                obj.get_type().name()?
+            );
+            match &pending.origin {
+                Some(origin) => warn(py, &format!("Invalid {origin}: {reason}."))?,
+                None => warn(py, &format!("{reason}. Its handler is not registered."))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+struct MethodTables {
+    registry: TypeMap,
+    annotations: TypeMap,
+    classes: TypeMap,
+    pending: Vec<PendingRegistration>,
+    exact: TypeMap,
+    before: TypeMap,
+    after: TypeMap,
+    around: TypeMap,
+}
+
+impl MethodTables {
+    fn new(registry: TypeMap) -> Self {
+        MethodTables {
+            registry,
+            annotations: TypeMap::new(),
+            classes: TypeMap::new(),
+            pending: Vec::new(),
+            exact: TypeMap::new(),
+            before: TypeMap::new(),
+            after: TypeMap::new(),
+            around: TypeMap::new(),
+        }
+    }
+
+    fn traverse(&self, visit: &PyVisit<'_>) -> Result<(), PyTraverseError> {
+        for table in [
+            &self.registry,
+            &self.annotations,
+            &self.classes,
+            &self.exact,
+            &self.before,
+            &self.after,
+            &self.around,
+        ] {
+            table.traverse(visit)?;
+        }
+        for pending in self.pending.iter() {
+            visit.call(&pending.func)?;
+        }
+        Ok(())
+    }
+
+    fn clone_ref(&self, py: Python) -> Self {
+        MethodTables {
+            registry: self.registry.clone_ref(py),
+            annotations: self.annotations.clone_ref(py),
+            classes: self.classes.clone_ref(py),
+            pending: self.pending.iter().map(|p| p.clone_ref(py)).collect(),
+            exact: self.exact.clone_ref(py),
+            before: self.before.clone_ref(py),
+            after: self.after.clone_ref(py),
+            around: self.around.clone_ref(py),
+        }
+    }
+
+    /// Overlay the entries of `child` over these tables, so that the child's
+    /// registrations shadow the ones inherited from a parent dispatcher.
+    fn overlay(mut self, py: Python, child: &MethodTables) -> Self {
+        // Annotations are only kept for registry entries, so the child's
+        // registrations also drop any inherited annotation for their types.
+        for typ in child.registry.keys() {
+            self.annotations.remove(typ);
+        }
+        self.annotations.update(py, &child.annotations);
+        self.classes.update(py, &child.classes);
+        self.pending
+            .extend(child.pending.iter().map(|p| p.clone_ref(py)));
+        for qualifier in [
+            MethodQualifier::Primary,
+            MethodQualifier::Exact,
+            MethodQualifier::Before,
+            MethodQualifier::After,
+            MethodQualifier::Around,
+        ] {
+            self.table_mut(qualifier).update(py, child.table(qualifier));
+        }
+        self
+    }
+
+    fn insert(
+        &mut self,
+        py: Python,
+        qualifier: MethodQualifier,
+        dispatch_type: &DispatchType,
+        func: PyObject,
+    ) -> PyResult<()> {
+        let key = dispatch_type.key(py);
+        if dispatch_type.class_object {
+            self.classes.insert(py, key, func)?;
+            return Ok(());
+        }
+        if qualifier == MethodQualifier::Primary {
+            match &dispatch_type.annotation {
+                Some(annotation) => {
+                    self.annotations
+                        .insert(py, key.clone_ref(py), annotation.clone_ref(py))?;
+                }
+                None => {
+                    self.annotations.remove(&key);
+                }
+            }
+        }
+        self.table_mut(qualifier).insert(py, key, func)?;
+        Ok(())
+    }
+
+    /// The annotations registered for the types of `dispatch_types`, which
+    /// `check_parameterizations` compares them with.
+    fn registered_annotations(
+        &self,
+        py: Python,
+        dispatch_types: &[DispatchType],
+    ) -> Vec<Option<PyObject>> {
+        dispatch_types
+            .iter()
+            .map(|dispatch_type| {
+                self.annotations
+                    .get(&dispatch_type.key(py))
+                    .map(|annotation| annotation.clone_ref(py))
+            })
+            .collect()
+    }
+
+    fn insert_pending(
+        &mut self,
+        name: String,
+        func: PyObject,
+        qualifier: MethodQualifier,
+        origin: Option<String>,
+    ) {
+        self.pending
+            .retain(|p| p.name != name || p.qualifier != qualifier);
+        self.pending.push(PendingRegistration {
+            name,
+            func,
+            qualifier,
+            origin,
+        });
+    }
+
+    /// The distinct type names of the pending registrations.
+    fn pending_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for pending in self.pending.iter() {
+            if !names.contains(&pending.name) {
+                names.push(pending.name.clone());
+            }
+        }
+        names
+    }
+
+    /// Register the pending handlers whose type name is resolved to a class by
+    /// `resolutions`, and drop those whose type name turned out not to name a
+    /// class, which are added to `dropped`. Returns whether any registration was
+    /// resolved or dropped.
+    ///
+    /// If a handler cannot be registered, the error is returned and the
+    /// registration is kept pending along with all those after it, so it is
+    /// reported again rather than silently dropping any handlers.
+    fn apply_resolutions(
+        &mut self,
+        py: Python,
+        resolutions: &Resolutions,
+        dropped: &mut Vec<PendingRegistration>,
+    ) -> PyResult<bool> {
+        let mut changed = false;
+        let mut remaining = std::mem::take(&mut self.pending).into_iter();
+        let mut result = Ok(());
+        for pending in remaining.by_ref() {
+            if resolutions
+                .invalid
+                .iter()
+                .any(|(name, _)| *name == pending.name)
+            {
+                dropped.push(pending);
+                changed = true;
+                continue;
+            }
+            let typ = match resolutions
+                .classes
+                .iter()
+                .find(|(name, _)| *name == pending.name)
+            {
+                Some((_, typ)) => typ,
+                None => {
+                    self.pending.push(pending);
+                    continue;
+                }
+            };
+            let dispatch_type = DispatchType {
+                typ: typ.clone().unbind(),
+                annotation: None,
+                class_object: false,
+            };
+            match self.insert(
+                py,
+                pending.qualifier,
+                &dispatch_type,
+                pending.func.clone_ref(py),
+            ) {
+                Ok(()) => changed = true,
+                Err(e) => {
+                    self.pending.push(pending);
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        self.pending.extend(remaining);
+        result.map(|()| changed)
+    }
+
+    /// Resolve the pending registrations of these tables, which are a snapshot
+    /// rather than those of a dispatcher, for a lookup of `cls`. Returns whether
+    /// any registration was resolved or dropped.
+    fn resolve_pending(&mut self, py: Python, cls: &Bound<'_, PyAny>) -> PyResult<bool> {
+        if self.pending.is_empty() {
+            return Ok(false);
+        }
+        let resolutions = Resolutions::resolve(py, self.pending_names(), Some(cls))?;
+        self.apply_resolutions(py, &resolutions, &mut Vec::new())
+    }
+
+    fn table(&self, qualifier: MethodQualifier) -> &TypeMap {
+        match qualifier {
+            MethodQualifier::Primary => &self.registry,
+            MethodQualifier::Exact => &self.exact,
+            MethodQualifier::Before => &self.before,
+            MethodQualifier::After => &self.after,
+            MethodQualifier::Around => &self.around,
+        }
+    }
+
+    fn table_mut(&mut self, qualifier: MethodQualifier) -> &mut TypeMap {
+        match qualifier {
+            MethodQualifier::Primary => &mut self.registry,
+            MethodQualifier::Exact => &mut self.exact,
+            MethodQualifier::Before => &mut self.before,
+            MethodQualifier::After => &mut self.after,
+            MethodQualifier::Around => &mut self.around,
+        }
+    }
+
+    /// Find the classes among `classes` for which dispatch would be ambiguous.
+    /// Pending registrations naming a class in their MRO are resolved first.
+    fn find_ambiguities(
+        &mut self,
+        py: Python,
+        classes: &[PyTypeReference],
+        options: &DispatchOptions,
+    ) -> PyResult<Vec<Ambiguity>> {
+        let mut ambiguities = Vec::new();
+        for typ in classes {
+            let cls = typ.wrapped().bind(py);
+            self.resolve_pending(py, cls)?;
+            if self.registry.contains_key(typ) {
+                continue;
+            }
+            if let MroMatch::Ambiguous(first, second) = match_mro(py, &self.registry, cls, options)?
+            {
+                ambiguities.push(Ambiguity {
+                    cls: typ.clone_ref(py),
+                    first,
+                    second,
+                });
+            }
+        }
+        Ok(ambiguities)
+    }
+
+    /// The handlers which apply to instances of `cls`, in the order in which they
+    /// are considered, as pairs of the type they were registered for (as shown in
+    /// `SingleDispatch.registry`) and the handler.
+    fn explain(
+        &self,
+        py: Python,
+        cls: &Bound<'_, PyAny>,
+        options: &DispatchOptions,
+    ) -> PyResult<Vec<(PyObject, PyObject)>> {
+        let mut candidates = Vec::new();
+        if let Some(exact) = self.exact.get(&PyTypeReference::new(cls.clone().unbind())) {
+            candidates.push((cls.clone().unbind(), display_handler(py, exact)?));
+        }
+        for typ in options.compose_mro(py, cls, self.registry.indexed())? {
+            if let Some(handler) = self.registry.get(&typ) {
+                let key = match self.annotations.get(&typ) {
+                    Some(annotation) => annotation.clone_ref(py),
+                    None => typ.wrapped().clone_ref(py),
+                };
+                candidates.push((key, display_handler(py, handler)?));
+            }
+        }
+        Ok(candidates)
+    }
+
+    /// Find the handler for instances of `cls`. If `use_default` is not set,
+    /// `None` is returned rather than falling back to the `object` handler.
+    fn find_dispatch(
+        &self,
+        py: Python,
+        cls: &Bound<'_, PyAny>,
+        options: &DispatchOptions,
+        use_default: bool,
+    ) -> PyResult<Option<PyObject>> {
+        let mut type_reference = PyTypeReference::new(cls.clone().unbind());
+        // Exact handlers take precedence, but are ignored entirely for subclasses.
+        let exact = match self.exact.get(&type_reference) {
+            Some(handler) if !options.fall_through => {
+                let primary = load_handler(py, handler)?;
+                return self
+                    .find_effective_method(py, cls, primary, options)
+                    .map(Some);
+            }
+            Some(handler) => Some(load_handler(py, handler)?),
+            None => None,
+        };
+        // A linearized order may prefer other types over `cls` itself.
+        if options.is_linearized() || !self.registry.contains_key(&type_reference) {
+            type_reference = match find_match(py, &self.registry, cls, options)? {
+                Some(m) => m,
+                None => {
+                    let obj_type =
+                        PyTypeReference::new(Builtins::cached(py)?.object_type.clone_ref(py));
+                    if !self.registry.contains_key(&obj_type) {
+                        return Err(PyRuntimeError::new_err(format!(
+                            "No dispatch function found for {cls}!"
+                        )));
+                    }
+                    obj_type
+                }
+            };
+            if !use_default
+                && exact.is_none()
+                && type_reference
+                    .wrapped()
+                    .is(&Builtins::cached(py)?.object_type)
+            {
+                return Ok(None);
+            }
+        }
+        let mut primary = match self.registry.get(&type_reference) {
+            Some(handler) => load_handler(py, handler)?,
+            None => {
+                return Err(PyRuntimeError::new_err(format!(
+                    "No dispatch function found for {cls}!"
+                )))
+            }
+        };
+        if options.validate_generics {
+            if let Some(annotation) = self.annotations.get(&type_reference) {
+                primary = validating_method(py, annotation.clone_ref(py), primary)?;
+            }
+        }
+        if options.fall_through {
+            primary =
+                self.find_fall_through_method(py, cls, exact, &type_reference, primary, options)?;
+        }
+        self.find_effective_method(py, cls, primary, options)
+            .map(Some)
+    }
+
+    /// Chain the `exact` handler for `cls` (if any) and `primary` (registered for
+    /// `matched`) with the handlers registered for the types following `matched`
+    /// in the composed MRO of `cls`. These are left as is if they are lazy
+    /// references, so they are only imported if the handlers before them return
+    /// `NotImplemented`.
+    fn find_fall_through_method(
+        &self,
+        py: Python,
+        cls: &Bound<'_, PyAny>,
+        exact: Option<PyObject>,
+        matched: &PyTypeReference,
+        primary: PyObject,
+        options: &DispatchOptions,
+    ) -> PyResult<PyObject> {
+        let mro = options.compose_mro(py, cls, self.registry.indexed())?;
+        let mut handlers: Vec<PyObject> = exact.into_iter().chain([primary]).collect();
+        for typ in mro.iter().skip_while(|typ| *typ != matched).skip(1) {
+            if let Some(handler) = self.registry.get(typ) {
+                let mut handler = handler.clone_ref(py);
+                if options.validate_generics {
+                    if let Some(annotation) = self.annotations.get(typ) {
+                        handler = validating_method(py, annotation.clone_ref(py), handler)?;
+                    }
+                }
+                handlers.push(handler);
+            }
+        }
+        fall_through_method(py, handlers)
+    }
+
+    /// Find the handler registered via `type[X]` for the class object `cls`, or
+    /// `None` if there is no such handler.
+    fn find_class_dispatch(
+        &self,
+        py: Python,
+        cls: &Bound<'_, PyAny>,
+        options: &DispatchOptions,
+    ) -> PyResult<PyObject> {
+        if self.classes.is_empty() {
+            return Ok(py.None());
+        }
+        let matched = find_match(py, &self.classes, cls, options)?;
+        match matched.as_ref().and_then(|m| self.classes.get(m)) {
+            Some(handler) => load_handler(py, handler),
+            None => Ok(py.None()),
+        }
+    }
+
+    fn find_effective_method(
+        &self,
+        py: Python,
+        cls: &Bound<'_, PyAny>,
+        primary: PyObject,
+        options: &DispatchOptions,
+    ) -> PyResult<PyObject> {
+        if self.before.is_empty() && self.after.is_empty() && self.around.is_empty() {
+            return Ok(primary);
+        }
+
+        let types = self
+            .registry
+            .indexed()
+            .chain(self.before.indexed())
+            .chain(self.after.indexed())
+            .chain(self.around.indexed());
+        let mro = options.compose_mro(py, cls, types)?;
+        let applicable = |table: &TypeMap| -> PyResult<Vec<PyObject>> {
+            mro.iter()
+                .filter_map(|typ| table.get(typ))
+                .map(|method| load_handler(py, method))
+                .collect()
+        };
+        effective_method(
+            py,
+            applicable(&self.before)?,
+            primary,
+            applicable(&self.after)?,
+            applicable(&self.around)?,
+        )
+    }
+
+    fn find_next_impl(
+        &self,
+        py: Python,
+        cls: &Bound<'_, PyAny>,
+        current: &Bound<'_, PyAny>,
+        options: &DispatchOptions,
+    ) -> PyResult<PyObject> {
+        let mro = options.compose_mro(py, cls, self.registry.indexed())?;
+        let exact = self.exact.get(&PyTypeReference::new(cls.clone().unbind()));
+        let mut handlers = exact
+            .into_iter()
+            .chain(mro.iter().filter_map(|typ| self.registry.get(typ)));
+        if !handlers.any(|handler| is_handler(py, handler, current)) {
+            return Err(PyRuntimeError::new_err(format!(
+                "{current} is not a dispatch function for {cls}!"
+            )));
+        }
+        match handlers.find(|handler| !is_handler(py, handler, current)) {
+            Some(handler) => load_handler(py, handler),
+            None => Err(PyRuntimeError::new_err(format!(
+                "No next dispatch function found for {cls} after {current}!"
+            ))),
+        }
+    }
+}
+
 struct SingleDispatchState {
-    registry: HashMap<PyTypeReference, PyObject>,
-    cache: HashMap<PyTypeReference, PyObject>,
+    /// Shared with lookups in progress, which search the tables without the lock
+    /// held. Changes are made to a copy if the tables are still in use.
+    tables: Arc<MethodTables>,
+    /// Incremented whenever the caches are invalidated, so that a handler found
+    /// without the lock held is only cached if nothing changed in the meantime.
+    epoch: u64,
+    cache: HashMap<PyTypeReference, PyObject, AddressHash>,
+    /// The handlers of `cache` for classes which their instances report as their
+    /// `__class__`, so that calls can look them up by the type of the argument.
+    type_cache: HashMap<PyTypeReference, PyObject, AddressHash>,
+    class_cache: HashMap<PyTypeReference, PyObject, AddressHash>,
+    next_cache: HashMap<(PyTypeReference, PyTypeReference), PyObject, AddressHash>,
     cache_token: Option<PyObject>,
+    parent_generation: u64,
+    on_register: Vec<PyObject>,
+    on_unregister: Vec<PyObject>,
+    entry_point_groups: Vec<String>,
 }
 
 impl SingleDispatchState {
-    fn find_impl(&mut self, py: Python, cls: Bound<'_, PyAny>) -> PyResult<PyObject> {
-        let cls_mro = get_obj_mro(&cls.clone())?;
-        let mro = compose_mro(py, cls.clone(), self.registry.keys())?;
-        let mut mro_match: Option<PyTypeReference> = None;
-        for typ in mro.iter() {
-            if self.registry.contains_key(typ) {
-                mro_match = Some(typ.clone_ref(py));
+    fn new(tables: MethodTables) -> Self {
+        SingleDispatchState {
+            tables: Arc::new(tables),
+            epoch: 0,
+            cache: HashMap::default(),
+            type_cache: HashMap::default(),
+            class_cache: HashMap::default(),
+            next_cache: HashMap::default(),
+            cache_token: None,
+            parent_generation: 0,
+            on_register: Vec::new(),
+            on_unregister: Vec::new(),
+            entry_point_groups: Vec::new(),
+        }
+    }
+
+    fn traverse(&self, visit: &PyVisit<'_>) -> Result<(), PyTraverseError> {
+        self.tables.traverse(visit)?;
+        for (typ, handler) in self
+            .cache
+            .iter()
+            .chain(self.type_cache.iter())
+            .chain(self.class_cache.iter())
+        {
+            visit.call(typ.wrapped())?;
+            visit.call(handler)?;
+        }
+        for ((cls, current), handler) in self.next_cache.iter() {
+            visit.call(cls.wrapped())?;
+            visit.call(current.wrapped())?;
+            visit.call(handler)?;
+        }
+        visit.call(&self.cache_token)?;
+        for observer in self.on_register.iter().chain(self.on_unregister.iter()) {
+            visit.call(observer)?;
+        }
+        Ok(())
+    }
+
+    /// Drop every reference held by the state, to break reference cycles.
+    fn clear(&mut self) {
+        self.tables = Arc::new(MethodTables::new(TypeMap::new()));
+        self.invalidate_caches();
+        self.cache_token = None;
+        self.on_register.clear();
+        self.on_unregister.clear();
+    }
+
+    fn tables_mut(&mut self, py: Python) -> &mut MethodTables {
+        if Arc::get_mut(&mut self.tables).is_none() {
+            self.tables = Arc::new(self.tables.clone_ref(py));
+        }
+        match Arc::get_mut(&mut self.tables) {
+            Some(tables) => tables,
+            None => unreachable!("the method tables were just copied"),
+        }
+    }
+
+    fn cache_mut(
+        &mut self,
+        class_object: bool,
+    ) -> &mut HashMap<PyTypeReference, PyObject, AddressHash> {
+        if class_object {
+            &mut self.class_cache
+        } else {
+            &mut self.cache
+        }
+    }
+
+    fn invalidate_caches(&mut self) {
+        self.epoch += 1;
+        self.cache.clear();
+        self.type_cache.clear();
+        self.class_cache.clear();
+        self.next_cache.clear();
+    }
+
+    /// Start tracking the ABC cache token once an ABC has been registered, as
+    /// dispatch then depends on virtual subclass registrations.
+    fn track_abcs(&mut self, py: Python) -> PyResult<()> {
+        if self.cache_token.is_some() {
+            return Ok(());
+        }
+        for typ in self
+            .tables
+            .registry
+            .keys()
+            .chain(self.tables.classes.keys())
+        {
+            if typ
+                .wrapped()
+                .bind(py)
+                .hasattr(intern!(py, "__abstractmethods__"))?
+            {
+                self.cache_token = Some(get_abc_cache_token(py)?.unbind());
+                break;
             }
+        }
+        Ok(())
+    }
 
-            if let Some(m) = mro_match {
-                let m = &m.clone_ref(py);
-                if self.registry.contains_key(typ)
-                    && !cls_mro.contains(typ)
-                    && !cls_mro.contains(m)
-                    && Builtins::cached(py)
-                        .issubclass(py, m.wrapped().bind(py), typ.wrapped().bind(py))
-                        .is_ok_and(|res| res)
-                {
-                    return Err(PyRuntimeError::new_err(format!(
-                        "Ambiguous dispatch: {m} or {typ}"
-                    )));
+    fn check_cache_token(&mut self, py: Python) -> PyResult<()> {
+        if let Some(cache_token) = &self.cache_token {
+            let current_token = get_abc_cache_token(py)?;
+            if current_token
+                .rich_compare(cache_token.bind(py), CompareOp::Ne)?
+                .is_truthy()?
+            {
+                self.invalidate_caches();
+                self.cache_token = Some(current_token.unbind());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What lookups of a dispatcher depend on in the dispatchers it is derived from.
+#[derive(Default)]
+struct Lineage {
+    /// Sum of the registration generations, which changes whenever any of them
+    /// registers a new handler.
+    generation: u64,
+    /// Whether any of them has registered an ABC, in which case the ABC cache
+    /// token must be tracked by the derived dispatcher as well.
+    uses_abcs: bool,
+    /// Whether an override of any of them may be active in some context.
+    overridden: bool,
+    /// Whether any of them has entry points left to load lazily.
+    lazy_entry_points: bool,
+}
+
+#[pyclass(weakref)]
+pub(crate) struct SingleDispatch {
+    lock: Mutex<SingleDispatchState>,
+    parent: Option<Py<SingleDispatch>>,
+    /// Identifies the dispatcher in the lookup state kept by overrides, which
+    /// may be shared with derived dispatchers.
+    id: u64,
+    generation: AtomicU64,
+    /// Whether an ABC has been registered, so that the caches of this dispatcher
+    /// and those derived from it depend on the ABC cache token.
+    uses_abcs: AtomicBool,
+    overrides: PyObject,
+    /// The number of overrides of this dispatcher which are alive, i.e. held by
+    /// some context. While there are none, neither the context variable needs
+    /// to be consulted nor the type-keyed cache bypassed.
+    live_overrides: Arc<AtomicUsize>,
+    /// Whether registrations by type name are pending, and the number of modules
+    /// in `sys.modules` when they were last looked up, so that they are only
+    /// looked up again before returning a cached handler once it changes.
+    has_pending: AtomicBool,
+    modules_seen: AtomicUsize,
+    options: DispatchOptions,
+    on_missing: Option<PyObject>,
+    lazy_entry_points: AtomicBool,
+    /// Arbitrary attributes set on the dispatcher. This is kept here rather
+    /// than in a `dict` slot so that it takes part in garbage collection.
+    attributes: Py<PyDict>,
+    /// The vectorcall function of the dispatcher, which the type points to.
+    #[cfg(not(feature = "abi3"))]
+    pub(crate) vectorcall: ffi::vectorcallfunc,
+}
+
+impl SingleDispatch {
+    fn state(&self) -> PyResult<MutexGuard<'_, SingleDispatchState>> {
+        lock_state(&self.lock)
+    }
+
+    fn new(
+        py: Python,
+        tables: MethodTables,
+        parent: Option<Py<SingleDispatch>>,
+        options: DispatchOptions,
+        on_missing: Option<PyObject>,
+    ) -> PyResult<Self> {
+        let overrides = py
+            .import(intern!(py, "contextvars"))?
+            .getattr(intern!(py, "ContextVar"))?
+            .call1((intern!(py, "singledispatch_overrides"),))?;
+        Ok(SingleDispatch {
+            has_pending: AtomicBool::new(!tables.pending.is_empty()),
+            modules_seen: AtomicUsize::new(0),
+            lock: Mutex::new(SingleDispatchState::new(tables)),
+            parent,
+            id: NEXT_DISPATCHER_ID.fetch_add(1, Ordering::Relaxed),
+            generation: AtomicU64::new(0),
+            uses_abcs: AtomicBool::new(false),
+            overrides: overrides.unbind(),
+            live_overrides: Arc::new(AtomicUsize::new(0)),
+            options,
+            on_missing,
+            lazy_entry_points: AtomicBool::new(false),
+            attributes: PyDict::new(py).unbind(),
+            #[cfg(not(feature = "abi3"))]
+            vectorcall: vectorcall::call,
+        })
+    }
+
+    /// Find the handler for instances of `cls`, or if `class_object` is set, the
+    /// `type[X]` handler for `cls` itself (which is `None` if there is none).
+    ///
+    /// If only the `object` handler matches, the `on_missing` callback is given a
+    /// chance to return a handler or to register one before looking up `cls` again.
+    fn find_handler(
+        &self,
+        py: Python,
+        cls: Bound<'_, PyAny>,
+        class_object: bool,
+    ) -> PyResult<PyObject> {
+        if class_object {
+            return self
+                .lookup_handler(py, cls, true, true)
+                .map(|handler| handler.unwrap_or_else(|| py.None()));
+        }
+        let use_default = self.on_missing.is_none()
+            && !self.lazy_entry_points.load(Ordering::Acquire)
+            && !self.parent_lineage(py).lazy_entry_points;
+        if let Some(handler) = self.lookup_handler(py, cls.clone(), false, use_default)? {
+            return Ok(handler);
+        }
+        if self.load_lazy_entry_points(py)? {
+            let use_default = self.on_missing.is_none();
+            if let Some(handler) = self.lookup_handler(py, cls.clone(), false, use_default)? {
+                return Ok(handler);
+            }
+        }
+        if let Some(on_missing) = &self.on_missing {
+            // The callback may register handlers, so it must be called without
+            // the lock held.
+            let handler = on_missing.call1(py, (&cls,))?;
+            if !handler.is_none(py) {
+                return Ok(handler);
+            }
+        }
+        self.lookup_handler(py, cls.clone(), false, true)?
+            .ok_or_else(|| {
+                PyRuntimeError::new_err(format!("No dispatch function found for {cls}!"))
+            })
+    }
+
+    /// Look up the handler for `cls` using the overrides active in the current
+    /// context, if any. See `MethodTables::find_dispatch` for `use_default`.
+    fn lookup_handler(
+        &self,
+        py: Python,
+        cls: Bound<'_, PyAny>,
+        class_object: bool,
+        use_default: bool,
+    ) -> PyResult<Option<PyObject>> {
+        if let Some(overrides) = self.active_overrides(py)? {
+            return overrides.get_or_find_impl(py, self, cls, class_object, use_default);
+        }
+        self.get_or_find_impl(py, cls, class_object, use_default)
+    }
+
+    /// Registration generation of this dispatcher and all of its ancestors.
+    fn lineage_generation(&self, py: Python) -> u64 {
+        let mut lineage = Lineage::default();
+        self.add_to_lineage(py, &mut lineage);
+        lineage.generation
+    }
+
+    /// Return the overrides active in the current context for this dispatcher
+    /// and its ancestors, or `None` if there are none.
+    fn active_overrides<'py>(&self, py: Python<'py>) -> PyResult<Option<ActiveOverrides<'py>>> {
+        if !self.lineage_overridden(py) {
+            return Ok(None);
+        }
+        let levels = self.lineage_overrides(py)?;
+        Ok(levels
+            .iter()
+            .rposition(Option::is_some)
+            .map(|nearest| ActiveOverrides { levels, nearest }))
+    }
+
+    /// Whether an override of this dispatcher or any of its ancestors may be
+    /// active in some context.
+    fn lineage_overridden(&self, py: Python) -> bool {
+        self.is_overridden() || self.parent_lineage(py).overridden
+    }
+
+    fn is_overridden(&self) -> bool {
+        self.live_overrides.load(Ordering::Acquire) > 0
+    }
+
+    /// The overrides active in the current context for each dispatcher from the
+    /// root ancestor down to this one.
+    fn lineage_overrides<'py>(
+        &self,
+        py: Python<'py>,
+    ) -> PyResult<Vec<Option<Bound<'py, DispatchOverrides>>>> {
+        let mut levels = match &self.parent {
+            Some(parent) => parent.borrow(py).lineage_overrides(py)?,
+            None => Vec::new(),
+        };
+        levels.push(self.context_overrides(py)?);
+        Ok(levels)
+    }
+
+    /// Return the overrides of this dispatcher active in the current context, if
+    /// any. The context variable is only consulted while any override is alive.
+    fn context_overrides<'py>(
+        &self,
+        py: Python<'py>,
+    ) -> PyResult<Option<Bound<'py, DispatchOverrides>>> {
+        if !self.is_overridden() {
+            return Ok(None);
+        }
+        let overrides = self
+            .overrides
+            .bind(py)
+            .call_method1(intern!(py, "get"), (py.None(),))?;
+        if overrides.is_none() {
+            Ok(None)
+        } else {
+            Ok(Some(overrides.downcast_into::<DispatchOverrides>()?))
+        }
+    }
+
+    /// The state of the ancestors of this dispatcher which its lookups depend on.
+    fn parent_lineage(&self, py: Python) -> Lineage {
+        let mut lineage = Lineage::default();
+        if let Some(parent) = &self.parent {
+            parent.borrow(py).add_to_lineage(py, &mut lineage);
+        }
+        lineage
+    }
+
+    fn add_to_lineage(&self, py: Python, lineage: &mut Lineage) {
+        lineage.generation += self.generation.load(Ordering::Acquire);
+        lineage.uses_abcs |= self.uses_abcs.load(Ordering::Acquire);
+        lineage.overridden |= self.is_overridden();
+        lineage.lazy_entry_points |= self.lazy_entry_points.load(Ordering::Acquire);
+        if let Some(parent) = &self.parent {
+            parent.borrow(py).add_to_lineage(py, lineage);
+        }
+    }
+
+    /// Return the method tables of this dispatcher merged over those of its
+    /// ancestors, with the overrides given for each level from the root down to
+    /// this dispatcher merged over the tables of that level.
+    fn overridden_tables(
+        &self,
+        py: Python,
+        levels: &[Option<Bound<'_, DispatchOverrides>>],
+    ) -> PyResult<MethodTables> {
+        let (overrides, ancestors) = match levels.split_last() {
+            Some((overrides, ancestors)) => (overrides.as_ref(), ancestors),
+            None => (None, levels),
+        };
+        let tables = match &self.parent {
+            Some(parent) => parent
+                .borrow(py)
+                .overridden_tables(py, ancestors)?
+                .overlay(py, &self.state()?.tables),
+            None => self.state()?.tables.as_ref().clone_ref(py),
+        };
+        Ok(match overrides {
+            Some(overrides) => tables.overlay(py, &overrides.get().state()?.tables),
+            None => tables,
+        })
+    }
+
+    fn merged_tables(&self, py: Python) -> PyResult<MethodTables> {
+        let state = self.state()?;
+        match self.inherited_tables(py, &state)? {
+            Some(tables) => Ok(tables),
+            None => Ok(state.tables.as_ref().clone_ref(py)),
+        }
+    }
+
+    /// Return the method tables of this dispatcher merged over those of its
+    /// ancestors, or `None` if this dispatcher does not have a parent.
+    fn inherited_tables(
+        &self,
+        py: Python,
+        state: &SingleDispatchState,
+    ) -> PyResult<Option<MethodTables>> {
+        match &self.parent {
+            Some(parent) => Ok(Some(
+                parent
+                    .borrow(py)
+                    .merged_tables(py)?
+                    .overlay(py, &state.tables),
+            )),
+            None => Ok(None),
+        }
+    }
+
+    fn check_caches(
+        &self,
+        py: Python,
+        state: &mut SingleDispatchState,
+        parent: &Lineage,
+    ) -> PyResult<()> {
+        if self.parent.is_some() {
+            if parent.generation != state.parent_generation {
+                state.invalidate_caches();
+                state.parent_generation = parent.generation;
+            }
+            if state.cache_token.is_none() && parent.uses_abcs {
+                state.cache_token = Some(get_abc_cache_token(py)?.unbind());
+            }
+        }
+        state.check_cache_token(py)
+    }
+
+    /// The tables to search for handlers for this dispatcher, including those of
+    /// its ancestors.
+    fn lookup_tables(
+        &self,
+        py: Python,
+        state: &SingleDispatchState,
+    ) -> PyResult<Arc<MethodTables>> {
+        Ok(match self.inherited_tables(py, state)? {
+            Some(tables) => Arc::new(tables),
+            None => state.tables.clone(),
+        })
+    }
+
+    // Handlers are looked up without the lock held, since importing a lazy handler
+    // or calling a custom linearization may well use the dispatcher, e.g. to
+    // register handlers when a handler module is imported.
+
+    fn get_or_find_impl(
+        &self,
+        py: Python,
+        cls: Bound<'_, PyAny>,
+        class_object: bool,
+        use_default: bool,
+    ) -> PyResult<Option<PyObject>> {
+        let type_reference = PyTypeReference::new(cls.clone().unbind());
+        self.resolve_pending(py, None)?;
+        {
+            let parent = self.parent_lineage(py);
+            let mut state = self.state()?;
+            self.check_caches(py, &mut state, &parent)?;
+            if let Some(handler) = state.cache_mut(class_object).get(&type_reference) {
+                return Ok(Some(handler.clone_ref(py)));
+            }
+        }
+        self.resolve_pending(py, Some(&cls))?;
+        let (tables, epoch) = {
+            let parent = self.parent_lineage(py);
+            let mut state = self.state()?;
+            self.check_caches(py, &mut state, &parent)?;
+            (self.lookup_tables(py, &state)?, state.epoch)
+        };
+        let handler_for_cls = if class_object {
+            Some(tables.find_class_dispatch(py, &cls, &self.options)?)
+        } else {
+            tables.find_dispatch(py, &cls, &self.options, use_default)?
+        };
+        if let Some(handler) = &handler_for_cls {
+            let own_class = match cls.downcast::<PyType>() {
+                Ok(typ) if !class_object => Builtins::cached(py)?.reports_own_class(typ)?,
+                _ => false,
+            };
+            let mut state = self.state()?;
+            if state.epoch == epoch {
+                if own_class {
+                    state
+                        .type_cache
+                        .insert(type_reference.clone_ref(py), handler.clone_ref(py));
                 }
-                mro_match = Some(m.clone_ref(py));
-                break;
+                state
+                    .cache_mut(class_object)
+                    .insert(type_reference, handler.clone_ref(py));
             }
         }
-        let impl_fn = match mro_match {
-            Some(v) => self.registry.get(&v).map(|it| it.clone_ref(py)),
-            None => None,
+        Ok(handler_for_cls)
+    }
+
+    /// Find the handler for a call with `obj` as its first argument.
+    pub(crate) fn handler_for(&self, py: Python, obj: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+        let is_class = obj.is_instance_of::<PyType>();
+        if !is_class {
+            if let Some(handler) = self.cached_handler(py, obj.get_type())? {
+                return Ok(handler);
+            }
+        }
+        // Like functools, dispatch on `__class__` rather than the type, so that
+        // proxies and mocks can stand in for the class they imitate.
+        let cls = match obj.getattr(intern!(py, "__class__")) {
+            Ok(cls) => cls,
+            Err(_) => return Err(PyTypeError::new_err("expected __class__ attribute for obj")),
         };
-        match impl_fn {
-            Some(f) => Ok(f),
-            None => {
-                let obj_type = PyTypeReference::new(Builtins::cached(py).object_type.clone_ref(py));
-                match self.registry.get(&obj_type) {
-                    Some(it) => Ok(it.clone_ref(py)),
-                    None => Err(PyRuntimeError::new_err(format!(
-                        "No dispatch function found for {cls}!"
-                    ))),
+        // Class objects are dispatched on the class itself if a matching
+        // `type[X]` handler is registered, and on their metaclass otherwise.
+        let mut handler = py.None();
+        if is_class {
+            handler = self.find_handler(py, obj.to_owned(), true)?;
+        }
+        if handler.is_none(py) {
+            handler = self.find_handler(py, cls, false)?;
+        }
+        Ok(handler)
+    }
+
+    /// Return the handler cached for instances of `typ`, if they are known to
+    /// report `typ` as their `__class__`, which is then not looked up at all.
+    /// Overrides are only taken into account by the full lookup, so there is no
+    /// cached handler while any is active in the current context.
+    fn cached_handler(&self, py: Python, typ: Bound<'_, PyType>) -> PyResult<Option<PyObject>> {
+        let parent = self.parent_lineage(py);
+        if (parent.overridden || self.is_overridden())
+            && self.lineage_overrides(py)?.iter().any(Option::is_some)
+        {
+            return Ok(None);
+        }
+        self.resolve_pending(py, None)?;
+        let type_reference = PyTypeReference::new(typ.into_any().unbind());
+        let mut state = self.state()?;
+        self.check_caches(py, &mut state, &parent)?;
+        Ok(state
+            .type_cache
+            .get(&type_reference)
+            .map(|handler| handler.clone_ref(py)))
+    }
+
+    fn get_or_find_next_impl(
+        &self,
+        py: Python,
+        cls: Bound<'_, PyAny>,
+        current: Bound<'_, PyAny>,
+    ) -> PyResult<PyObject> {
+        let cache_key = (
+            PyTypeReference::new(cls.clone().unbind()),
+            PyTypeReference::new(current.clone().unbind()),
+        );
+        self.resolve_pending(py, None)?;
+        {
+            let parent = self.parent_lineage(py);
+            let mut state = self.state()?;
+            self.check_caches(py, &mut state, &parent)?;
+            if let Some(handler) = state.next_cache.get(&cache_key) {
+                return Ok(handler.clone_ref(py));
+            }
+        }
+        self.resolve_pending(py, Some(&cls))?;
+        let (tables, epoch) = {
+            let parent = self.parent_lineage(py);
+            let mut state = self.state()?;
+            self.check_caches(py, &mut state, &parent)?;
+            (self.lookup_tables(py, &state)?, state.epoch)
+        };
+        let next_handler = tables.find_next_impl(py, &cls, &current, &self.options)?;
+        let mut state = self.state()?;
+        if state.epoch == epoch {
+            state
+                .next_cache
+                .insert(cache_key, next_handler.clone_ref(py));
+        }
+        Ok(next_handler)
+    }
+}
+
+impl SingleDispatch {
+    /// Invalidate all caches depending on the method tables of this dispatcher.
+    fn registry_changed(&self, py: Python, state: &mut SingleDispatchState) -> PyResult<()> {
+        state.track_abcs(py)?;
+        if state.cache_token.is_some() {
+            self.uses_abcs.store(true, Ordering::Release);
+        }
+        self.has_pending
+            .store(!state.tables.pending.is_empty(), Ordering::Release);
+        state.invalidate_caches();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        Ok(())
+    }
+
+    /// Resolve the pending registrations by type name of this dispatcher and its
+    /// ancestors which name a class in the MRO of `cls`, or with `cls` of `None`,
+    /// a class in a module imported since they were last looked up.
+    fn resolve_pending(&self, py: Python, cls: Option<&Bound<'_, PyAny>>) -> PyResult<()> {
+        if self.has_pending.load(Ordering::Acquire) {
+            let modules = imported_module_count(py)?;
+            let imported = self.modules_seen.swap(modules, Ordering::AcqRel) != modules;
+            if cls.is_some() || imported {
+                self.resolve_own_pending(py, cls)?;
+            }
+        }
+        match &self.parent {
+            Some(parent) => parent.borrow(py).resolve_pending(py, cls),
+            None => Ok(()),
+        }
+    }
+
+    fn resolve_own_pending(&self, py: Python, cls: Option<&Bound<'_, PyAny>>) -> PyResult<()> {
+        // The names are looked up without the lock held, since that may run
+        // arbitrary code. Registrations are matched by name when the results are
+        // applied, so any which were removed in the meantime stay removed.
+        let names = self.state()?.tables.pending_names();
+        let resolutions = Resolutions::resolve(py, names, cls)?;
+        if resolutions.is_empty() {
+            return Ok(());
+        }
+        let mut dropped = Vec::new();
+        {
+            let mut state = self.state()?;
+            let applied = state
+                .tables_mut(py)
+                .apply_resolutions(py, &resolutions, &mut dropped);
+            if !matches!(applied, Ok(false)) {
+                self.registry_changed(py, &mut state)?;
+            }
+            applied?;
+        }
+        resolutions.warn_dropped(py, &dropped)
+    }
+
+    fn register_cls(
+        &self,
+        py: Python<'_>,
+        cls: Bound<'_, PyAny>,
+        func: Bound<'_, PyAny>,
+        qualifier: MethodQualifier,
+        strict: bool,
+    ) -> PyResult<PyObject> {
+        let func = match func.downcast::<PyString>() {
+            Ok(path) => LazyHandler::new(path.to_cow()?.into_owned())?
+                .into_pyobject(py)?
+                .into_any(),
+            Err(_) => func,
+        };
+        if let Ok(name) = cls.downcast::<PyString>() {
+            let name = name.to_cow()?.into_owned();
+            check_type_name(&name)?;
+            if strict {
+                return Err(PyTypeError::new_err(format!(
+                    "'{name}' cannot be checked for ambiguities before it is resolved; strict registration requires a class."
+                )));
+            }
+            return self.register_pending(py, name, func.unbind(), qualifier, None);
+        }
+
+        let dispatch_types = valid_dispatch_types(py, &cls)?;
+        let unbound_func = func.unbind();
+        let observers = loop {
+            // Primary handlers are checked without the lock held, since that may
+            // call a custom linearization or compare annotations. Exact handlers
+            // never make dispatch ambiguous.
+            let generation = self.lineage_generation(py);
+            if qualifier == MethodQualifier::Primary {
+                let (tables, registered) = {
+                    let state = self.state()?;
+                    let tables = if strict {
+                        Some(self.lookup_tables(py, &state)?)
+                    } else {
+                        None
+                    };
+                    let registered = state.tables.registered_annotations(py, &dispatch_types);
+                    (tables, registered)
+                };
+                check_parameterizations(py, &dispatch_types, &registered)?;
+                if let Some(tables) = tables {
+                    self.check_registration(py, &tables, &dispatch_types, &unbound_func)?;
+                }
+            }
+            let mut state = self.state()?;
+            if qualifier == MethodQualifier::Primary && generation != self.lineage_generation(py) {
+                // The registry changed while it was being checked.
+                continue;
+            }
+            let inserted = dispatch_types.iter().try_for_each(|dispatch_type| {
+                state.tables_mut(py).insert(
+                    py,
+                    qualifier,
+                    dispatch_type,
+                    unbound_func.clone_ref(py),
+                )
+            });
+            // Some of the types may have been registered before one failed.
+            self.registry_changed(py, &mut state)?;
+            inserted?;
+            break clone_observers(py, &state.on_register, qualifier);
+        };
+        let func = display_handler(py, &unbound_func)?;
+        let changes: Vec<(PyObject, PyObject)> = dispatch_types
+            .iter()
+            .map(|typ| (typ.registry_key(py), func.clone_ref(py)))
+            .collect();
+        notify_observers(py, &observers, &changes)?;
+        Ok(func)
+    }
+
+    /// Register `func` for the dotted type name `name`, to be resolved later.
+    fn register_pending(
+        &self,
+        py: Python<'_>,
+        name: String,
+        func: PyObject,
+        qualifier: MethodQualifier,
+        origin: Option<String>,
+    ) -> PyResult<PyObject> {
+        let observers = {
+            let mut state = self.state()?;
+            state.tables_mut(py).insert_pending(
+                name.clone(),
+                func.clone_ref(py),
+                qualifier,
+                origin,
+            );
+            self.registry_changed(py, &mut state)?;
+            clone_observers(py, &state.on_register, qualifier)
+        };
+        let func = display_handler(py, &func)?;
+        let changes = [(name.into_py_any(py)?, func.clone_ref(py))];
+        notify_observers(py, &observers, &changes)?;
+        Ok(func)
+    }
+
+    /// Remove the primary handlers registered by this dispatcher for `cls`,
+    /// returning the registry keys and handlers which were removed.
+    fn unregister_cls(
+        &self,
+        py: Python<'_>,
+        cls: &Bound<'_, PyAny>,
+        qualifier: MethodQualifier,
+    ) -> PyResult<Vec<(PyObject, PyObject)>> {
+        // Every type of a union is checked before any of them is removed, so that
+        // an invalid one doesn't leave the others half unregistered.
+        let dispatch_types = match cls.downcast::<PyString>() {
+            Ok(_) => Vec::new(),
+            Err(_) => valid_dispatch_types(py, cls)?,
+        };
+        let object_type = &Builtins::cached(py)?.object_type;
+        if qualifier != MethodQualifier::Exact
+            && dispatch_types
+                .iter()
+                .any(|d| !d.class_object && d.typ.bind(py).is(object_type))
+        {
+            return Err(PyTypeError::new_err(
+                "The default dispatch function cannot be unregistered.",
+            ));
+        }
+        let mut state = self.state()?;
+        let tables = state.tables_mut(py);
+        let mut removed = Vec::new();
+        if let Ok(name) = cls.downcast::<PyString>() {
+            let name = name.to_cow()?.into_owned();
+            let position = tables
+                .pending
+                .iter()
+                .position(|p| p.name == name && p.qualifier == qualifier);
+            if let Some(i) = position {
+                let pending = tables.pending.remove(i);
+                removed.push((cls.clone().unbind(), display_handler(py, &pending.func)?));
+            }
+        } else {
+            for dispatch_type in dispatch_types {
+                let key = dispatch_type.key(py);
+                let func = if qualifier == MethodQualifier::Exact {
+                    tables.exact.remove(&key)
+                } else if dispatch_type.class_object {
+                    tables.classes.remove(&key)
+                } else {
+                    tables.annotations.remove(&key);
+                    tables.registry.remove(&key)
+                };
+                if let Some(func) = func {
+                    removed.push((dispatch_type.registry_key(py), display_handler(py, &func)?));
+                }
+            }
+        }
+        if removed.is_empty() {
+            return Err(PyKeyError::new_err(cls.clone().unbind()));
+        }
+        self.registry_changed(py, &mut state)?;
+        Ok(removed)
+    }
+
+    /// Register the handlers named by the entry points in `group`. Invalid entry
+    /// points are skipped with a warning, so one broken plugin does not prevent
+    /// the others from being loaded. This includes those whose type name turns
+    /// out not to name a class once it is resolved.
+    fn register_entry_points(&self, py: Python<'_>, group: &str) -> PyResult<()> {
+        for (name, value) in read_entry_points(py, group)? {
+            let origin = format!("entry point '{name} = {value}' in group '{group}'");
+            let register = || -> PyResult<PyObject> {
+                check_type_name(&name)?;
+                let func = LazyHandler::new(value)?.into_py_any(py)?;
+                self.register_pending(
+                    py,
+                    name,
+                    func,
+                    MethodQualifier::Primary,
+                    Some(origin.clone()),
+                )
+            };
+            if let Err(e) = register() {
+                warn(py, &format!("Invalid {origin}: {e}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Register the handlers of entry point groups which were loaded lazily by
+    /// this dispatcher or any of its ancestors, if that has not happened yet.
+    /// Returns whether any groups were loaded.
+    fn load_lazy_entry_points(&self, py: Python<'_>) -> PyResult<bool> {
+        let mut loaded = match &self.parent {
+            Some(parent) => parent.borrow(py).load_lazy_entry_points(py)?,
+            None => false,
+        };
+        if self.lazy_entry_points.swap(false, Ordering::AcqRel) {
+            let groups = std::mem::take(&mut self.state()?.entry_point_groups);
+            for group in groups.iter() {
+                self.register_entry_points(py, group)?;
+            }
+            loaded |= !groups.is_empty();
+        }
+        Ok(loaded)
+    }
+
+    /// Raise an error if registering `func` for `dispatch_types` would make
+    /// dispatch ambiguous for any known subclass of the registered types.
+    fn check_registration(
+        &self,
+        py: Python,
+        tables: &MethodTables,
+        dispatch_types: &[DispatchType],
+        func: &PyObject,
+    ) -> PyResult<()> {
+        let mut tables = tables.clone_ref(py);
+        let mut keys = Vec::with_capacity(dispatch_types.len());
+        for dispatch_type in dispatch_types.iter() {
+            tables.insert(
+                py,
+                MethodQualifier::Primary,
+                dispatch_type,
+                func.clone_ref(py),
+            )?;
+            if !dispatch_type.class_object {
+                keys.push(dispatch_type.key(py));
+            }
+        }
+        let classes = get_known_subclasses(py, keys.iter())?;
+        let messages: Vec<String> = tables
+            .find_ambiguities(py, &classes, &self.options)?
+            .iter()
+            .filter(|a| keys.iter().any(|key| a.involves(key)))
+            .map(|a| format!("{}: {} or {}", a.cls, a.first, a.second))
+            .collect();
+        if messages.is_empty() {
+            Ok(())
+        } else {
+            Err(PyRuntimeError::new_err(format!(
+                "Ambiguous dispatch for {}",
+                messages.join("; ")
+            )))
+        }
+    }
+
+    fn register_qualified(
+        slf: Py<Self>,
+        py: Python<'_>,
+        cls: Bound<'_, PyAny>,
+        func: Option<Bound<'_, PyAny>>,
+        qualifier: MethodQualifier,
+        strict: bool,
+    ) -> PyResult<PyObject> {
+        let singledispatch = slf.borrow(py);
+        check_qualifier(py, &cls, qualifier)?;
+        if is_valid_dispatch_type(py, &cls) || cls.is_instance_of::<PyString>() {
+            match func {
+                Some(actual_func) => {
+                    singledispatch.register_cls(py, cls, actual_func, qualifier, strict)
                 }
+                None => match (PartialSingleDispatchRegistration {
+                    singledispatch: slf.clone_ref(py),
+                    cls: cls.unbind(),
+                    qualifier,
+                    strict,
+                })
+                .into_pyobject(py)
+                {
+                    Ok(v) => Ok(v.into_py_any(py)?),
+                    Err(e) => Err(e),
+                },
             }
-        }
-    }
-
-    fn get_or_find_impl(&mut self, py: Python, cls: Bound<'_, PyAny>) -> PyResult<PyObject> {
-        let free_cls = cls.unbind();
-        let type_reference = PyTypeReference::new(free_cls.clone_ref(py));
-
-        match self.cache.get(&type_reference) {
-            Some(handler) => Ok(handler.clone_ref(py)),
-            None => {
-                let handler_for_cls = match self.registry.get(&type_reference) {
-                    Some(handler) => handler.clone_ref(py),
-                    None => self.find_impl(py, free_cls.bind(py).clone())?,
-                };
-                self.cache
-                    .insert(type_reference, handler_for_cls.clone_ref(py));
-                Ok(handler_for_cls)
+        } else {
+            match func {
+                Some(f) => singledispatch.register_with_type_annotations(py, cls, f),
+                None => match TypingModule::cached(py)?.overload_annotations(py, &cls)? {
+                    Some(annotations) => {
+                        singledispatch.register_overloads(py, annotations, cls, qualifier, strict)
+                    }
+                    None => Err(PyTypeError::new_err(format!(
+                        "invalid first argument to `register()`. {cls} must be a class or union type."
+                    ))),
+                },
             }
         }
     }
-}
-
-#[pyclass]
-pub(crate) struct SingleDispatch {
-    lock: Mutex<SingleDispatchState>,
-}
 
-impl SingleDispatch {
-    fn register_cls(
+    /// Register `func` for the first parameter of each of its `@typing.overload`
+    /// signatures, given by `annotations`. All of them are checked before any is
+    /// registered.
+    fn register_overloads(
         &self,
         py: Python<'_>,
-        cls: Bound<'_, PyAny>,
+        annotations: Vec<Bound<'_, PyAny>>,
         func: Bound<'_, PyAny>,
+        qualifier: MethodQualifier,
+        strict: bool,
     ) -> PyResult<PyObject> {
-        let typing_module = TypingModule::cached(py);
-        match self.lock.lock() {
-            Ok(mut state) => {
-                let unbound_func = func.unbind();
-                if typing_module.is_union_type(py, &cls)? {
-                    match typing_module.get_args(py, &cls) {
-                        Ok(tuple) => {
-                            for tp in tuple.bind(py).iter() {
-                                state.registry.insert(
-                                    PyTypeReference::new(tp.unbind()),
-                                    unbound_func.clone_ref(py),
-                                );
-                            }
-                        }
-                        Err(e) => return Err(e),
-                    }
-                } else {
-                    state.registry.insert(
-                        PyTypeReference::new(cls.into_pyobject(py)?.unbind()),
-                        unbound_func.clone_ref(py),
-                    );
-                }
-                if state.cache_token.is_none()
-                    && unbound_func
-                        .getattr(py, intern!(py, "__abstractmethods__"))
-                        .is_ok()
-                {
-                    state.cache_token = Some(get_abc_cache_token(py)?.unbind());
-                }
-                state.cache.clear();
-                Ok(unbound_func)
+        for annotation in &annotations {
+            if !is_valid_dispatch_type(py, annotation) && !annotation.is_instance_of::<PyString>() {
+                return Err(PyTypeError::new_err(format!(
+                    "Invalid overload of {func}: {annotation} is not a class or union type."
+                )));
             }
-            Err(e) => Err(PyRuntimeError::new_err(format!(
-                "Singledispatch mutex poisoned: {e}"
-            ))),
+            check_qualifier(py, annotation, qualifier)?;
         }
+        for annotation in annotations {
+            self.register_cls(py, annotation, func.clone(), qualifier, strict)?;
+        }
+        Ok(func.unbind())
     }
 
     fn register_with_type_annotations(
@@ -200,120 +1866,862 @@ impl SingleDispatch {
 
 #[pymethods]
 impl SingleDispatch {
+    fn __traverse__(&self, visit: PyVisit<'_>) -> Result<(), PyTraverseError> {
+        visit.call(&self.parent)?;
+        visit.call(&self.overrides)?;
+        visit.call(&self.on_missing)?;
+        visit.call(&self.attributes)?;
+        self.options.traverse(&visit)?;
+        // The lock may be held by this very thread if the collection was
+        // triggered while resolving a handler. Skipping the state then is safe,
+        // since unreported references only keep objects alive.
+        if let Ok(state) = self.lock.try_lock() {
+            state.traverse(&visit)?;
+        }
+        Ok(())
+    }
+
+    fn __clear__(slf: &Bound<'_, Self>) {
+        if let Ok(mut this) = slf.try_borrow_mut() {
+            this.lock
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clear();
+            this.parent = None;
+            this.on_missing = None;
+            this.options.mro = None;
+            this.attributes.bind(slf.py()).clear();
+        }
+    }
+
+    #[getter]
+    fn __dict__(&self, py: Python<'_>) -> Py<PyDict> {
+        self.attributes.clone_ref(py)
+    }
+
+    /// Like the `__dict__` of a function, the attributes of the dispatcher take
+    /// precedence over the `__doc__` and `__module__` of its class, so that e.g.
+    /// `functools.update_wrapper` can set them.
+    fn __getattribute__(slf: &Bound<'_, Self>, name: Bound<'_, PyString>) -> PyResult<PyObject> {
+        let py = slf.py();
+        if is_instance_attribute(&name)? {
+            if let Some(value) = slf.borrow().attributes.bind(py).get_item(&name)? {
+                return Ok(value.unbind());
+            }
+        }
+        unsafe {
+            Bound::from_owned_ptr_or_err(
+                py,
+                ffi::PyObject_GenericGetAttr(slf.as_ptr(), name.as_ptr()),
+            )
+        }
+        .map(Bound::unbind)
+    }
+
+    /// Only called for names which are not found on the class, so dispatching
+    /// and the methods of the dispatcher are not slowed down by the attributes.
+    fn __getattr__(&self, py: Python<'_>, name: Bound<'_, PyString>) -> PyResult<PyObject> {
+        match self.attributes.bind(py).get_item(&name)? {
+            Some(value) => Ok(value.unbind()),
+            None => Err(PyAttributeError::new_err(format!(
+                "'SingleDispatch' object has no attribute '{name}'"
+            ))),
+        }
+    }
+
+    fn __setattr__(
+        slf: &Bound<'_, Self>,
+        name: Bound<'_, PyString>,
+        value: Bound<'_, PyAny>,
+    ) -> PyResult<()> {
+        let py = slf.py();
+        if !is_instance_attribute(&name)? && is_class_attribute(&slf.get_type(), &name)? {
+            // Methods and properties of the dispatcher can't be replaced.
+            return Err(PyAttributeError::new_err(format!(
+                "'SingleDispatch' object attribute '{name}' is read-only"
+            )));
+        }
+        slf.borrow().attributes.bind(py).set_item(name, value)
+    }
+
+    fn __delattr__(slf: &Bound<'_, Self>, name: Bound<'_, PyString>) -> PyResult<()> {
+        let py = slf.py();
+        let this = slf.borrow();
+        let attributes = this.attributes.bind(py);
+        if !attributes.contains(&name)? {
+            return Err(PyAttributeError::new_err(format!(
+                "'SingleDispatch' object has no attribute '{name}'"
+            )));
+        }
+        attributes.del_item(name)
+    }
+
     #[new]
-    fn __new__<'py>(py: Python, func: Bound<'py, PyAny>) -> Self {
-        let mut registry = HashMap::new();
-        let py_object_type = Builtins::cached(py).object_type.clone_ref(py);
+    #[pyo3(signature = (func, *, validate_generics=false, on_missing=None, fall_through=false, mro=None, resolution="mro"))]
+    fn __new__<'py>(
+        py: Python,
+        func: Bound<'py, PyAny>,
+        validate_generics: bool,
+        on_missing: Option<Bound<'py, PyAny>>,
+        fall_through: bool,
+        mro: Option<Bound<'py, PyAny>>,
+        resolution: &str,
+    ) -> PyResult<Self> {
+        let resolution = Resolution::parse(resolution)?;
+        if mro.is_some() && resolution == Resolution::Ordered {
+            return Err(PyValueError::new_err(
+                "A custom `mro` cannot be combined with resolution='ordered'.",
+            ));
+        }
+        let mut registry = TypeMap::new();
+        let py_object_type = Builtins::cached(py)?.object_type.clone_ref(py);
         let f = func.unbind();
-        registry.insert(PyTypeReference::new(py_object_type), f);
+        registry.insert(py, PyTypeReference::new(py_object_type), f)?;
 
-        SingleDispatch {
-            lock: Mutex::new(SingleDispatchState {
-                registry,
-                cache: HashMap::new(),
-                cache_token: None,
-            }),
-        }
+        SingleDispatch::new(
+            py,
+            MethodTables::new(registry),
+            None,
+            DispatchOptions {
+                validate_generics,
+                fall_through,
+                resolution,
+                mro: mro.map(Bound::unbind),
+            },
+            on_missing.map(Bound::unbind),
+        )
     }
 
-    #[pyo3(signature = (obj, /, *args, **kwargs))]
+    /// The arguments are passed on to the handler exactly as they were given,
+    /// without building a new tuple.
+    #[pyo3(signature = (*args, **kwargs))]
     fn __call__(
         &self,
         py: Python<'_>,
-        obj: Bound<'_, PyAny>,
         args: &Bound<'_, PyTuple>,
         kwargs: Option<&Bound<'_, PyDict>>,
     ) -> PyResult<Py<PyAny>> {
-        match obj.getattr(intern!(py, "__class__")) {
-            Ok(cls) => {
-                let mut all_args = Vec::with_capacity(1 + args.len());
-                all_args.insert(0, obj);
-                all_args.extend(args);
-
-                match self.dispatch(py, cls) {
-                    Ok(handler) => handler.call(py, PyTuple::new(py, all_args)?, kwargs),
-                    Err(e) => Err(e),
-                }
+        let obj = match args.get_borrowed_item(0) {
+            Ok(obj) => obj,
+            Err(_) => return Err(missing_argument()),
+        };
+        self.handler_for(py, &obj)?.call(py, args, kwargs)
+    }
+
+    fn dispatch(&self, py: Python<'_>, cls: Bound<'_, PyAny>) -> PyResult<PyObject> {
+        self.find_handler(py, cls, false)
+    }
+
+    fn next_handler(
+        &self,
+        py: Python<'_>,
+        cls: Bound<'_, PyAny>,
+        current: Bound<'_, PyAny>,
+    ) -> PyResult<PyObject> {
+        if let Some(overrides) = self.active_overrides(py)? {
+            return overrides.get_or_find_next_impl(py, self, cls, current);
+        }
+        self.get_or_find_next_impl(py, cls, current)
+    }
+
+    fn derive(slf: Py<Self>, py: Python<'_>) -> PyResult<SingleDispatch> {
+        let (options, on_missing) = {
+            let parent = slf.borrow(py);
+            let on_missing = parent.on_missing.as_ref().map(|f| f.clone_ref(py));
+            (parent.options.clone_ref(py), on_missing)
+        };
+        SingleDispatch::new(
+            py,
+            MethodTables::new(TypeMap::new()),
+            Some(slf),
+            options,
+            on_missing,
+        )
+    }
+
+    /// Read-only mapping of registered types (or the parameterized generics they
+    /// were registered with) to their primary handlers, including inherited ones.
+    #[getter]
+    fn registry<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let tables = self.merged_tables(py)?;
+        let registry = PyDict::new(py);
+        let display = |func: &PyObject| display_handler(py, func);
+        for (typ, func) in tables.registry.iter() {
+            match tables.annotations.get(typ) {
+                Some(annotation) => registry.set_item(annotation, display(func)?)?,
+                None => registry.set_item(typ.wrapped(), display(func)?)?,
+            }
+        }
+        let generic_alias_type = TypingModule::cached(py)?
+            .generic_alias_type
+            .wrapped()
+            .bind(py);
+        for (typ, func) in tables.classes.iter() {
+            let annotation =
+                generic_alias_type.call1((py.get_type::<PyType>(), (typ.wrapped(),)))?;
+            registry.set_item(annotation, display(func)?)?;
+        }
+        for pending in tables.pending.iter() {
+            if pending.qualifier == MethodQualifier::Primary {
+                registry.set_item(&pending.name, display(&pending.func)?)?;
             }
-            Err(_) => Err(PyTypeError::new_err("expected __class__ attribute for obj")),
         }
+        py.import(intern!(py, "types"))?
+            .getattr(intern!(py, "MappingProxyType"))?
+            .call1((registry,))
     }
 
-    fn dispatch(&self, py: Python<'_>, cls: Bound<'_, PyAny>) -> PyResult<PyObject> {
-        match self.lock.lock() {
-            Ok(mut state) => {
-                if let Some(cache_token) = &state.cache_token {
-                    let current_token = get_abc_cache_token(py)?;
-                    if current_token
-                        .rich_compare(cache_token.bind(py), CompareOp::Eq)
-                        .is_ok()
-                    {
-                        state.cache.clear();
-                        state.cache_token = Some(current_token.unbind());
+    /// Return the `(type, handler)` pairs applicable to instances of `cls`, in the
+    /// order in which they are considered for dispatch. Unless dispatch for `cls`
+    /// is ambiguous, the first handler is the one `dispatch(cls)` returns (before
+    /// any auxiliary methods are applied).
+    fn explain<'py>(
+        &self,
+        py: Python<'py>,
+        cls: Bound<'py, PyAny>,
+    ) -> PyResult<Bound<'py, PyList>> {
+        let mut tables = self.merged_tables(py)?;
+        tables.resolve_pending(py, &cls)?;
+        PyList::new(py, tables.explain(py, &cls, &self.options)?)
+    }
+
+    #[pyo3(name = "override")]
+    fn override_(
+        slf: Py<Self>,
+        py: Python<'_>,
+        cls: Bound<'_, PyAny>,
+        func: Bound<'_, PyAny>,
+    ) -> PyResult<DispatchOverride> {
+        if !is_valid_dispatch_type(py, &cls) {
+            return Err(PyTypeError::new_err(format!(
+                "invalid first argument to `override()`. {cls} must be a class or union type."
+            )));
+        }
+        // Handlers may be given as a lazy reference, as for `register()`.
+        let func = match func.downcast::<PyString>() {
+            Ok(path) => LazyHandler::new(path.to_cow()?.into_owned())?.into_py_any(py)?,
+            Err(_) => func.unbind(),
+        };
+        Ok(DispatchOverride {
+            singledispatch: slf,
+            cls: cls.unbind(),
+            func,
+            token: None,
+        })
+    }
+
+    /// Return a list of `(cls, first, second)` tuples for each of `classes` (or
+    /// every known subclass of a registered type, if not given) for which
+    /// dispatch is ambiguous between the registered types `first` and `second`.
+    #[pyo3(signature = (classes=None))]
+    fn check<'py>(
+        &self,
+        py: Python<'py>,
+        classes: Option<Bound<'py, PyAny>>,
+    ) -> PyResult<Bound<'py, PyList>> {
+        let mut tables = self.merged_tables(py)?;
+        let classes = match classes {
+            Some(classes) => {
+                let mut types = Vec::new();
+                for item in classes.try_iter()? {
+                    let item = item?;
+                    if !item.is_instance_of::<PyType>() {
+                        return Err(PyTypeError::new_err(format!(
+                            "invalid argument to `check()`. {item} is not a class."
+                        )));
                     }
+                    types.push(PyTypeReference::new(item.unbind()));
                 }
-
-                state.get_or_find_impl(py, cls)
+                types
             }
-            Err(e) => Err(PyRuntimeError::new_err(format!(
-                "Singledispatch mutex poisoned: {e}"
-            ))),
+            None => get_known_subclasses(py, tables.registry.keys())?,
+        };
+        let ambiguities = PyList::empty(py);
+        for a in tables.find_ambiguities(py, &classes, &self.options)? {
+            ambiguities.append((a.cls.wrapped(), a.first.wrapped(), a.second.wrapped()))?;
         }
+        Ok(ambiguities)
     }
 
-    #[pyo3(signature = (cls, func=None))]
+    #[pyo3(signature = (cls, func=None, *, strict=false, exact=false))]
     fn register(
         slf: Py<Self>,
         py: Python<'_>,
         cls: Bound<'_, PyAny>,
         func: Option<Bound<'_, PyAny>>,
+        strict: bool,
+        exact: bool,
     ) -> PyResult<PyObject> {
-        let singledispatch = slf.borrow(py);
-        if is_valid_dispatch_type(py, &cls) {
-            match func {
-                Some(actual_func) => singledispatch.register_cls(py, cls, actual_func),
-                None => match PartialSingleDispatchRegistration::__new__(slf.clone_ref(py), cls)
-                    .into_pyobject(py)
-                {
-                    Ok(v) => Ok(v.into_py_any(py)?),
-                    Err(e) => Err(e),
-                },
-            }
+        let qualifier = if exact {
+            MethodQualifier::Exact
         } else {
-            match func {
-                Some(f) => singledispatch.register_with_type_annotations(py, cls, f),
-                None => Err(PyTypeError::new_err(format!(
-                    "invalid first argument to `register()`. {cls} must be a class or union type."
-                ))),
+            MethodQualifier::Primary
+        };
+        Self::register_qualified(slf, py, cls, func, qualifier, strict)
+    }
+
+    /// Register the handlers contributed by the entry points of installed
+    /// distributions in `group`, where each entry point maps a dotted type name to
+    /// a `"module:function"` reference. If `lazy` is set, the entry points are only
+    /// read the first time no handler other than the default one matches.
+    #[pyo3(signature = (group, *, lazy=false))]
+    fn load_entry_points(&self, py: Python<'_>, group: String, lazy: bool) -> PyResult<()> {
+        if !lazy {
+            return self.register_entry_points(py, &group);
+        }
+        let mut state = self.state()?;
+        if !state.entry_point_groups.contains(&group) {
+            state.entry_point_groups.push(group);
+        }
+        self.lazy_entry_points.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Remove the handler registered for `cls` (or the exact handler, if `exact`
+    /// is set), notifying `on_unregister` observers. Handlers inherited from a
+    /// parent dispatcher cannot be removed.
+    #[pyo3(signature = (cls, *, exact=false))]
+    fn unregister(&self, py: Python<'_>, cls: Bound<'_, PyAny>, exact: bool) -> PyResult<()> {
+        let qualifier = if exact {
+            MethodQualifier::Exact
+        } else {
+            MethodQualifier::Primary
+        };
+        let removed = self.unregister_cls(py, &cls, qualifier)?;
+        let observers = clone_observers(py, &self.state()?.on_unregister, MethodQualifier::Primary);
+        notify_observers(py, &observers, &removed)
+    }
+
+    /// Call `callback(cls, func)` whenever a handler is registered. Returns
+    /// `callback`, so this may be used as a decorator.
+    fn on_register(&self, py: Python<'_>, callback: Bound<'_, PyAny>) -> PyResult<PyObject> {
+        let callback = callback.unbind();
+        self.state()?.on_register.push(callback.clone_ref(py));
+        Ok(callback)
+    }
+
+    /// Call `callback(cls, func)` whenever a handler is unregistered. Returns
+    /// `callback`, so this may be used as a decorator.
+    fn on_unregister(&self, py: Python<'_>, callback: Bound<'_, PyAny>) -> PyResult<PyObject> {
+        let callback = callback.unbind();
+        self.state()?.on_unregister.push(callback.clone_ref(py));
+        Ok(callback)
+    }
+
+    /// Remove `callback` from the `on_register` and `on_unregister` observers.
+    fn remove_observer(&self, py: Python<'_>, callback: Bound<'_, PyAny>) -> PyResult<()> {
+        // Observers are compared with `==` like `atexit.unregister` does, since
+        // bound methods are created anew on every access. That may run arbitrary
+        // code, so it is done without the lock held.
+        let observers: Vec<PyObject> = {
+            let state = self.state()?;
+            state
+                .on_register
+                .iter()
+                .chain(state.on_unregister.iter())
+                .map(|o| o.clone_ref(py))
+                .collect()
+        };
+        let mut matching = Vec::new();
+        for observer in observers {
+            if observer.bind(py).eq(&callback)? {
+                matching.push(observer);
             }
         }
+        if matching.is_empty() {
+            return Err(PyValueError::new_err(format!(
+                "{callback} is not an observer"
+            )));
+        }
+        let mut state = self.state()?;
+        state
+            .on_register
+            .retain(|o| !matching.iter().any(|m| m.is(o)));
+        state
+            .on_unregister
+            .retain(|o| !matching.iter().any(|m| m.is(o)));
+        Ok(())
+    }
+
+    #[pyo3(signature = (cls, func=None))]
+    fn register_before(
+        slf: Py<Self>,
+        py: Python<'_>,
+        cls: Bound<'_, PyAny>,
+        func: Option<Bound<'_, PyAny>>,
+    ) -> PyResult<PyObject> {
+        Self::register_qualified(slf, py, cls, func, MethodQualifier::Before, false)
+    }
+
+    #[pyo3(signature = (cls, func=None))]
+    fn register_after(
+        slf: Py<Self>,
+        py: Python<'_>,
+        cls: Bound<'_, PyAny>,
+        func: Option<Bound<'_, PyAny>>,
+    ) -> PyResult<PyObject> {
+        Self::register_qualified(slf, py, cls, func, MethodQualifier::After, false)
+    }
+
+    #[pyo3(signature = (cls, func=None))]
+    fn register_around(
+        slf: Py<Self>,
+        py: Python<'_>,
+        cls: Bound<'_, PyAny>,
+        func: Option<Bound<'_, PyAny>>,
+    ) -> PyResult<PyObject> {
+        Self::register_qualified(slf, py, cls, func, MethodQualifier::Around, false)
     }
 }
 
-#[pyclass]
+#[pyclass(weakref)]
 struct PartialSingleDispatchRegistration {
     singledispatch: Py<SingleDispatch>,
     cls: PyObject,
+    qualifier: MethodQualifier,
+    strict: bool,
 }
 
 #[pymethods]
 impl PartialSingleDispatchRegistration {
+    fn __traverse__(&self, visit: PyVisit<'_>) -> Result<(), PyTraverseError> {
+        visit.call(&self.singledispatch)?;
+        visit.call(&self.cls)
+    }
+
     #[new]
     fn __new__<'py>(singledispatch: Py<SingleDispatch>, cls: Bound<'py, PyAny>) -> Self {
         PartialSingleDispatchRegistration {
             singledispatch,
             cls: cls.unbind(),
+            qualifier: MethodQualifier::Primary,
+            strict: false,
         }
     }
 
     #[pyo3(signature = (func))]
     fn __call__(&self, py: Python<'_>, func: Bound<'_, PyAny>) -> PyResult<Py<PyAny>> {
         let singledispatch = self.singledispatch.borrow(py);
-        singledispatch.register_cls(py, self.cls.clone_ref(py).into_bound(py), func)
+        singledispatch.register_cls(
+            py,
+            self.cls.clone_ref(py).into_bound(py),
+            func,
+            self.qualifier,
+            self.strict,
+        )
+    }
+}
+
+/// Handlers overridden in a particular context, stored in the dispatcher's
+/// context variable. Resolution against the overrides is cached here rather than
+/// in the dispatcher so the overrides never leak into the shared cache.
+#[pyclass(frozen)]
+struct DispatchOverrides {
+    lock: Mutex<OverridesState>,
+    /// The count of live overrides of the dispatcher they were entered for.
+    live: Arc<AtomicUsize>,
+}
+
+impl Drop for DispatchOverrides {
+    fn drop(&mut self) {
+        self.live.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+struct OverridesState {
+    tables: Arc<MethodTables>,
+    /// Lookup state for the dispatcher the overrides were entered for and any
+    /// dispatcher derived from it, along with the overrides of their ancestors.
+    lookups: Vec<OverrideLookup>,
+}
+
+struct OverrideLookup {
+    dispatcher: u64,
+    ancestors: Vec<Option<Py<DispatchOverrides>>>,
+    /// The tables of the dispatcher merged with all overrides, and the caches.
+    state: SingleDispatchState,
+}
+
+impl OverrideLookup {
+    fn is_for(&self, dispatcher: u64, ancestors: &[Option<Bound<'_, DispatchOverrides>>]) -> bool {
+        self.dispatcher == dispatcher
+            && self.ancestors.len() == ancestors.len()
+            && self
+                .ancestors
+                .iter()
+                .zip(ancestors)
+                .all(|(a, b)| a.as_ref().map(Py::as_ptr) == b.as_ref().map(Bound::as_ptr))
+    }
+
+    fn traverse(&self, visit: &PyVisit<'_>) -> Result<(), PyTraverseError> {
+        for overrides in self.ancestors.iter() {
+            visit.call(overrides)?;
+        }
+        self.state.traverse(visit)
+    }
+}
+
+/// The overrides active for a dispatcher, for each level from the root ancestor
+/// down to the dispatcher itself. The overrides at `nearest` are the ones closest
+/// to the dispatcher, and keep the lookup state for it.
+struct ActiveOverrides<'py> {
+    levels: Vec<Option<Bound<'py, DispatchOverrides>>>,
+    nearest: usize,
+}
+
+impl ActiveOverrides<'_> {
+    fn nearest(&self) -> PyResult<MutexGuard<'_, OverridesState>> {
+        match &self.levels[self.nearest] {
+            Some(overrides) => overrides.get().state(),
+            None => Err(PyRuntimeError::new_err("Override is not active")),
+        }
+    }
+
+    /// Return the lookup state for `singledispatch` with these overrides, if it
+    /// is up to date with the registry.
+    fn lookup_mut<'a>(
+        &self,
+        py: Python,
+        state: &'a mut OverridesState,
+        singledispatch: &SingleDispatch,
+        generation: u64,
+    ) -> PyResult<Option<&'a mut SingleDispatchState>> {
+        let ancestors = &self.levels[..self.nearest];
+        let lookup = state
+            .lookups
+            .iter_mut()
+            .find(|lookup| lookup.is_for(singledispatch.id, ancestors));
+        match lookup {
+            Some(lookup) if lookup.state.parent_generation == generation => {
+                lookup.state.check_cache_token(py)?;
+                Ok(Some(&mut lookup.state))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Return a snapshot of the tables to search for handlers along with the
+    /// epoch of the lookup state, (re)building the state if it is out of date.
+    fn lookup_tables(
+        &self,
+        py: Python,
+        singledispatch: &SingleDispatch,
+    ) -> PyResult<(Arc<MethodTables>, u64, u64)> {
+        let generation = singledispatch.lineage_generation(py);
+        {
+            let mut nearest = self.nearest()?;
+            if let Some(state) = self.lookup_mut(py, &mut nearest, singledispatch, generation)? {
+                return Ok((state.tables.clone(), state.epoch, generation));
+            }
+        }
+        let tables = singledispatch.overridden_tables(py, &self.levels[..=self.nearest])?;
+        let mut state = SingleDispatchState::new(tables);
+        state.track_abcs(py)?;
+        state.parent_generation = generation;
+        let snapshot = (state.tables.clone(), state.epoch, generation);
+        let ancestors = &self.levels[..self.nearest];
+        let mut nearest = self.nearest()?;
+        nearest
+            .lookups
+            .retain(|lookup| !lookup.is_for(singledispatch.id, ancestors));
+        nearest.lookups.push(OverrideLookup {
+            dispatcher: singledispatch.id,
+            ancestors: ancestors
+                .iter()
+                .map(|overrides| overrides.as_ref().map(|o| o.clone().unbind()))
+                .collect(),
+            state,
+        });
+        Ok(snapshot)
+    }
+
+    fn get_or_find_impl(
+        &self,
+        py: Python,
+        singledispatch: &SingleDispatch,
+        cls: Bound<'_, PyAny>,
+        class_object: bool,
+        use_default: bool,
+    ) -> PyResult<Option<PyObject>> {
+        let type_reference = PyTypeReference::new(cls.clone().unbind());
+        singledispatch.resolve_pending(py, None)?;
+        let generation = singledispatch.lineage_generation(py);
+        let mut nearest = self.nearest()?;
+        if let Some(state) = self.lookup_mut(py, &mut nearest, singledispatch, generation)? {
+            if let Some(handler) = state.cache_mut(class_object).get(&type_reference) {
+                return Ok(Some(handler.clone_ref(py)));
+            }
+        }
+        drop(nearest);
+        singledispatch.resolve_pending(py, Some(&cls))?;
+        let (tables, epoch, generation) = self.lookup_tables(py, singledispatch)?;
+        let handler_for_cls = if class_object {
+            Some(tables.find_class_dispatch(py, &cls, &singledispatch.options)?)
+        } else {
+            tables.find_dispatch(py, &cls, &singledispatch.options, use_default)?
+        };
+        if let Some(handler) = &handler_for_cls {
+            let mut nearest = self.nearest()?;
+            if let Some(state) = self.lookup_mut(py, &mut nearest, singledispatch, generation)? {
+                if state.epoch == epoch {
+                    state
+                        .cache_mut(class_object)
+                        .insert(type_reference, handler.clone_ref(py));
+                }
+            }
+        }
+        Ok(handler_for_cls)
+    }
+
+    fn get_or_find_next_impl(
+        &self,
+        py: Python,
+        singledispatch: &SingleDispatch,
+        cls: Bound<'_, PyAny>,
+        current: Bound<'_, PyAny>,
+    ) -> PyResult<PyObject> {
+        let cache_key = (
+            PyTypeReference::new(cls.clone().unbind()),
+            PyTypeReference::new(current.clone().unbind()),
+        );
+        singledispatch.resolve_pending(py, None)?;
+        let generation = singledispatch.lineage_generation(py);
+        let mut nearest = self.nearest()?;
+        if let Some(state) = self.lookup_mut(py, &mut nearest, singledispatch, generation)? {
+            if let Some(handler) = state.next_cache.get(&cache_key) {
+                return Ok(handler.clone_ref(py));
+            }
+        }
+        drop(nearest);
+        singledispatch.resolve_pending(py, Some(&cls))?;
+        let (tables, epoch, generation) = self.lookup_tables(py, singledispatch)?;
+        let next_handler = tables.find_next_impl(py, &cls, &current, &singledispatch.options)?;
+        let mut nearest = self.nearest()?;
+        if let Some(state) = self.lookup_mut(py, &mut nearest, singledispatch, generation)? {
+            if state.epoch == epoch {
+                state
+                    .next_cache
+                    .insert(cache_key, next_handler.clone_ref(py));
+            }
+        }
+        Ok(next_handler)
+    }
+}
+
+#[pymethods]
+impl DispatchOverrides {
+    fn __traverse__(&self, visit: PyVisit<'_>) -> Result<(), PyTraverseError> {
+        if let Ok(state) = self.lock.try_lock() {
+            state.tables.traverse(&visit)?;
+            for lookup in state.lookups.iter() {
+                lookup.traverse(&visit)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn __clear__(&self) {
+        // Take the state out first, so that the handlers are only dropped once
+        // the lock has been released.
+        let _state = match self.lock.lock() {
+            Ok(mut state) => std::mem::replace(
+                &mut *state,
+                OverridesState {
+                    tables: Arc::new(MethodTables::new(TypeMap::new())),
+                    lookups: Vec::new(),
+                },
+            ),
+            Err(_) => return,
+        };
+    }
+}
+
+impl DispatchOverrides {
+    fn state(&self) -> PyResult<MutexGuard<'_, OverridesState>> {
+        lock_state(&self.lock)
+    }
+}
+
+/// Context manager returned by `SingleDispatch.override`.
+#[pyclass(weakref)]
+struct DispatchOverride {
+    singledispatch: Py<SingleDispatch>,
+    cls: PyObject,
+    func: PyObject,
+    token: Option<PyObject>,
+}
+
+#[pymethods]
+impl DispatchOverride {
+    fn __traverse__(&self, visit: PyVisit<'_>) -> Result<(), PyTraverseError> {
+        visit.call(&self.singledispatch)?;
+        visit.call(&self.cls)?;
+        visit.call(&self.func)?;
+        visit.call(&self.token)
+    }
+
+    fn __enter__(&mut self, py: Python<'_>) -> PyResult<PyObject> {
+        if self.token.is_some() {
+            return Err(PyRuntimeError::new_err("Override is already active"));
+        }
+        let singledispatch = self.singledispatch.borrow(py);
+        let mut tables = match singledispatch.context_overrides(py)? {
+            Some(overrides) => overrides.get().state()?.tables.as_ref().clone_ref(py),
+            None => MethodTables::new(TypeMap::new()),
+        };
+        let cls = self.cls.bind(py);
+        for dispatch_type in valid_dispatch_types(py, cls)?.iter() {
+            // Overrides also shadow any exact handler registered for the class.
+            for qualifier in [MethodQualifier::Primary, MethodQualifier::Exact] {
+                tables.insert(py, qualifier, dispatch_type, self.func.clone_ref(py))?;
+            }
+        }
+        singledispatch.live_overrides.fetch_add(1, Ordering::AcqRel);
+        let overrides = DispatchOverrides {
+            lock: Mutex::new(OverridesState {
+                tables: Arc::new(tables),
+                lookups: Vec::new(),
+            }),
+            live: singledispatch.live_overrides.clone(),
+        };
+
+        let token = singledispatch
+            .overrides
+            .bind(py)
+            .call_method1(intern!(py, "set"), (overrides,))?;
+        self.token = Some(token.unbind());
+        display_handler(py, &self.func)
+    }
+
+    #[pyo3(signature = (*_args))]
+    fn __exit__(&mut self, py: Python<'_>, _args: &Bound<'_, PyTuple>) -> PyResult<bool> {
+        match self.token.take() {
+            Some(token) => {
+                self.singledispatch
+                    .borrow(py)
+                    .overrides
+                    .bind(py)
+                    .call_method1(intern!(py, "reset"), (token,))?;
+                Ok(false)
+            }
+            None => Err(PyRuntimeError::new_err("Override is not active")),
+        }
+    }
+}
+
+/// Whether `name` is defined by the class `cls` or one of its bases, as opposed
+/// to its metaclass.
+fn is_class_attribute(cls: &Bound<'_, PyType>, name: &Bound<'_, PyString>) -> PyResult<bool> {
+    let py = cls.py();
+    for base in cls.mro().iter() {
+        if base.getattr(intern!(py, "__dict__"))?.contains(name)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Class attributes which each dispatcher may set for itself.
+fn is_instance_attribute(name: &Bound<'_, PyString>) -> PyResult<bool> {
+    Ok(matches!(&*name.to_cow()?, "__doc__" | "__module__"))
+}
+
+/// Only one primary handler is kept per class, so refuse to register a
+/// parameterized generic whose origin is already registered with another
+/// parameterization, e.g. `list[str]` after `list[int]`, rather than silently
+/// replacing its handler. `registered` holds the annotation registered for each
+/// of `dispatch_types`.
+///
+/// Annotations are compared with `==`, which runs Python code, so no lock may be
+/// held.
+fn check_parameterizations(
+    py: Python,
+    dispatch_types: &[DispatchType],
+    registered: &[Option<PyObject>],
+) -> PyResult<()> {
+    for (i, dispatch_type) in dispatch_types.iter().enumerate() {
+        let annotation = match &dispatch_type.annotation {
+            Some(annotation) if !dispatch_type.class_object => annotation.bind(py),
+            _ => continue,
+        };
+        let others = registered[i].iter().chain(
+            dispatch_types[..i]
+                .iter()
+                .filter(|other| !other.class_object && other.typ.is(&dispatch_type.typ))
+                .filter_map(|other| other.annotation.as_ref()),
+        );
+        for other in others {
+            if annotation.ne(other)? {
+                return Err(PyTypeError::new_err(format!(
+                    "{annotation} conflicts with {}, which is registered for the same class. Register a single handler for {} instead.",
+                    other.bind(py),
+                    dispatch_type.typ.bind(py).name()?
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that handlers for `cls` may be registered with `qualifier`.
+fn check_qualifier(py: Python, cls: &Bound<'_, PyAny>, qualifier: MethodQualifier) -> PyResult<()> {
+    if qualifier != MethodQualifier::Primary
+        && valid_dispatch_types(py, cls).is_ok_and(|types| types.iter().any(|typ| typ.class_object))
+    {
+        return Err(PyTypeError::new_err(format!(
+            "{cls} may only be registered as a primary handler."
+        )));
+    }
+    if qualifier == MethodQualifier::Exact
+        && valid_dispatch_types(py, cls)
+            .is_ok_and(|types| types.iter().any(|typ| typ.annotation.is_some()))
+    {
+        return Err(PyTypeError::new_err(format!(
+            "{cls} cannot be registered as an exact handler; exact handlers must be registered for classes."
+        )));
+    }
+    Ok(())
+}
+
+/// The error raised when a dispatcher is called without any positional argument.
+pub(crate) fn missing_argument() -> PyErr {
+    PyTypeError::new_err("singledispatch function requires at least 1 positional argument")
+}
+
+/// Check that `name` can be registered as a type name.
+fn check_type_name(name: &str) -> PyResult<()> {
+    if name.contains('.') {
+        Ok(())
+    } else {
+        Err(PyTypeError::new_err(format!(
+            "invalid first argument to `register()`. '{name}' must be a dotted type name."
+        )))
     }
 }
 
 #[pyfunction]
-pub(crate) fn singledispatch<'py>(py: Python, func: Bound<'py, PyAny>) -> PyResult<SingleDispatch> {
-    Ok(SingleDispatch::__new__(py, func))
+#[pyo3(signature = (func, *, validate_generics=false, on_missing=None, fall_through=false, mro=None, resolution="mro"))]
+pub(crate) fn singledispatch<'py>(
+    py: Python<'py>,
+    func: Bound<'py, PyAny>,
+    validate_generics: bool,
+    on_missing: Option<Bound<'py, PyAny>>,
+    fall_through: bool,
+    mro: Option<Bound<'py, PyAny>>,
+    resolution: &str,
+) -> PyResult<Bound<'py, SingleDispatch>> {
+    let dispatcher = SingleDispatch::__new__(
+        py,
+        func,
+        validate_generics,
+        on_missing,
+        fall_through,
+        mro,
+        resolution,
+    )?;
+    let dispatcher = Bound::new(py, dispatcher)?;
+    // Every other dispatcher is created from this one, or derived from it.
+    #[cfg(not(feature = "abi3"))]
+    vectorcall::enable(&dispatcher)?;
+    Ok(dispatcher)
 }
@@ -0,0 +1,70 @@
+use pyo3::exceptions::{PyRuntimeWarning, PyTypeError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use pyo3::{intern, Bound, PyAny, PyResult, Python};
+use std::collections::BTreeMap;
+use std::ffi::CString;
+
+/// Return the entry points of the installed distributions in `group`.
+fn group_entry_points<'py>(py: Python<'py>, group: &str) -> PyResult<Bound<'py, PyAny>> {
+    let entry_points = py
+        .import(intern!(py, "importlib.metadata"))?
+        .getattr(intern!(py, "entry_points"))?;
+    let kwargs = PyDict::new(py);
+    kwargs.set_item(intern!(py, "group"), group)?;
+    match entry_points.call((), Some(&kwargs)) {
+        Ok(selected) => Ok(selected),
+        // Python 3.9 only supports returning a dict of all groups.
+        Err(e) if e.is_instance_of::<PyTypeError>(py) => entry_points
+            .call0()?
+            .call_method1(intern!(py, "get"), (group, ())),
+        Err(e) => Err(e),
+    }
+}
+
+pub(crate) fn warn(py: Python, message: &str) -> PyResult<()> {
+    let message = CString::new(message)?;
+    PyErr::warn(py, &py.get_type::<PyRuntimeWarning>(), &message, 2)
+}
+
+/// Read the `(type name, handler reference)` pairs named by the entry points in
+/// `group`, ordered by type name.
+///
+/// Type names which are contributed with different handler references by more
+/// than one entry point are skipped with a `RuntimeWarning`.
+pub(crate) fn read_entry_points(py: Python, group: &str) -> PyResult<Vec<(String, String)>> {
+    let mut handlers: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for entry_point in group_entry_points(py, group)?.try_iter()? {
+        let entry_point = entry_point?;
+        let name: String = entry_point.getattr(intern!(py, "name"))?.extract()?;
+        let value: String = entry_point.getattr(intern!(py, "value"))?.extract()?;
+        // Extras (`module:function [extra]`) have no meaning for handlers.
+        let value = value
+            .split('[')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_string();
+        let values = handlers.entry(name).or_default();
+        if !values.contains(&value) {
+            values.push(value);
+        }
+    }
+
+    let mut entry_points = Vec::with_capacity(handlers.len());
+    for (name, mut values) in handlers {
+        if values.len() == 1 {
+            entry_points.push((name, values.remove(0)));
+        } else {
+            values.sort();
+            warn(
+                py,
+                &format!(
+                    "Conflicting entry points in group '{group}' for '{name}': {}",
+                    values.join(", ")
+                ),
+            )?;
+        }
+    }
+    Ok(entry_points)
+}
@@ -0,0 +1,208 @@
+use crate::singledispatch::mro::get_obj_mro;
+use pyo3::exceptions::{PyRuntimeError, PyTypeError};
+use pyo3::ffi;
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyTuple, PyType};
+use pyo3::{
+    intern, pyclass, pymethods, Bound, IntoPyObjectExt, PyAny, PyObject, PyResult, PyTraverseError,
+    PyVisit, Python,
+};
+use std::sync::Mutex;
+
+/// Fully qualified `module.qualname` names of the classes in the MRO of `cls`.
+pub(crate) fn mro_type_names<'py>(
+    cls: &Bound<'py, PyAny>,
+) -> PyResult<Vec<(String, Bound<'py, PyType>)>> {
+    let py = cls.py();
+    let mut names = Vec::new();
+    for item in get_obj_mro(cls)? {
+        if let Ok(typ) = item.wrapped().bind(py).downcast::<PyType>() {
+            let module = typ.getattr(intern!(py, "__module__"))?;
+            let qualname = typ.getattr(intern!(py, "__qualname__"))?;
+            names.push((format!("{module}.{qualname}"), typ.clone()));
+        }
+    }
+    Ok(names)
+}
+
+/// The number of modules in `sys.modules`, which changes as modules are
+/// imported, so that pending type names are only looked up again when it does.
+pub(crate) fn imported_module_count(py: Python) -> PyResult<usize> {
+    // `sys.modules` is borrowed, so this doesn't even import `sys`.
+    let modules = unsafe { ffi::PySys_GetObject(c"modules".as_ptr()) };
+    if modules.is_null() {
+        return Ok(0);
+    }
+    unsafe { Bound::from_borrowed_ptr(py, modules) }.len()
+}
+
+/// Resolve the dotted type name `name` without importing anything.
+///
+/// The name resolves if it matches one of the classes in `mro_names`, or if a
+/// prefix of the name is a module which is already present in `sys.modules`.
+/// Returns `None` if the name cannot be resolved yet, and otherwise the object
+/// it names, which may turn out not to be a class.
+pub(crate) fn resolve_type_name<'py>(
+    py: Python<'py>,
+    name: &str,
+    mro_names: &[(String, Bound<'py, PyType>)],
+) -> PyResult<Option<Bound<'py, PyAny>>> {
+    if let Some((_, typ)) = mro_names.iter().find(|(n, _)| n == name) {
+        return Ok(Some(typ.clone().into_any()));
+    }
+
+    let sys_modules = py
+        .import(intern!(py, "sys"))?
+        .getattr(intern!(py, "modules"))?
+        .downcast_into::<PyDict>()?;
+    for (i, _) in name.rmatch_indices('.') {
+        let (module_name, qualname) = (&name[..i], &name[i + 1..]);
+        if let Some(module) = sys_modules.get_item(module_name)? {
+            let mut obj = module;
+            for attr in qualname.split('.') {
+                match obj.getattr(attr) {
+                    Ok(v) => obj = v,
+                    // The module may still be in the process of being initialized.
+                    Err(_) => return Ok(None),
+                }
+            }
+            return Ok(Some(obj));
+        }
+    }
+    Ok(None)
+}
+
+/// A handler given as a `"module:qualname"` reference, which is imported the
+/// first time it is selected for dispatch.
+#[pyclass(frozen)]
+pub(crate) struct LazyHandler {
+    path: String,
+    /// The handler once it has been loaded. This is a mutex rather than a
+    /// `GILOnceCell` so that it can be traversed and cleared by the garbage
+    /// collector, as a handler may well refer back to its dispatcher.
+    handler: Mutex<Option<PyObject>>,
+}
+
+impl LazyHandler {
+    pub(crate) fn new(path: String) -> PyResult<Self> {
+        match path.split_once(':') {
+            Some((module, qualname)) if !module.is_empty() && !qualname.is_empty() => {
+                Ok(LazyHandler {
+                    path,
+                    handler: Mutex::new(None),
+                })
+            }
+            _ => Err(PyTypeError::new_err(format!(
+                "invalid dispatch function '{path}'. Lazy references must have the form \"module:function\"."
+            ))),
+        }
+    }
+
+    pub(crate) fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The handler, if it has already been loaded.
+    pub(crate) fn loaded(&self, py: Python) -> Option<PyObject> {
+        match self.handler.lock() {
+            Ok(handler) => handler.as_ref().map(|handler| handler.clone_ref(py)),
+            Err(_) => None,
+        }
+    }
+
+    fn import(&self, py: Python) -> PyResult<PyObject> {
+        let (module, qualname) = self.path.split_once(':').unwrap_or_default();
+        let mut obj = py.import(module)?.into_any();
+        for attr in qualname.split('.') {
+            obj = obj.getattr(attr)?;
+        }
+        Ok(obj.unbind())
+    }
+
+    pub(crate) fn load(&self, py: Python) -> PyResult<PyObject> {
+        if let Some(handler) = self.loaded(py) {
+            return Ok(handler);
+        }
+        // The lock is not held while importing, which may well use the handler
+        // itself. If two threads load it at once, the first one to finish wins.
+        let handler = self.import(py).map_err(|cause| {
+            let err = PyRuntimeError::new_err(format!(
+                "Could not load dispatch function '{}': {cause}",
+                self.path
+            ));
+            err.set_cause(py, Some(cause));
+            err
+        })?;
+        let mut loaded = self
+            .handler
+            .lock()
+            .map_err(|e| PyRuntimeError::new_err(format!("Lazy handler mutex poisoned: {e}")))?;
+        Ok(loaded.get_or_insert(handler).clone_ref(py))
+    }
+}
+
+#[pymethods]
+impl LazyHandler {
+    fn __traverse__(&self, visit: PyVisit<'_>) -> Result<(), PyTraverseError> {
+        if let Ok(handler) = self.handler.try_lock() {
+            visit.call(&*handler)?;
+        }
+        Ok(())
+    }
+
+    fn __clear__(&self) {
+        // Take the handler out first, so that it is only dropped once the lock
+        // has been released.
+        let _handler = match self.handler.lock() {
+            Ok(mut handler) => handler.take(),
+            Err(_) => return,
+        };
+    }
+
+    #[pyo3(signature = (*args, **kwargs))]
+    fn __call__(
+        &self,
+        py: Python<'_>,
+        args: &Bound<'_, PyTuple>,
+        kwargs: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<PyObject> {
+        self.load(py)?.call(py, args, kwargs)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("<lazy dispatch function '{}'>", self.path)
+    }
+}
+
+/// Return the actual handler for a registered handler, loading it if it is a
+/// lazy reference.
+pub(crate) fn load_handler(py: Python, handler: &PyObject) -> PyResult<PyObject> {
+    match handler.downcast_bound::<LazyHandler>(py) {
+        Ok(lazy) => lazy.get().load(py),
+        Err(_) => Ok(handler.clone_ref(py)),
+    }
+}
+
+/// Return the registered `handler` as shown to users: lazy references are shown
+/// as their path until they are loaded.
+pub(crate) fn display_handler(py: Python, handler: &PyObject) -> PyResult<PyObject> {
+    match handler.downcast_bound::<LazyHandler>(py) {
+        Ok(lazy) => match lazy.get().loaded(py) {
+            Some(loaded) => Ok(loaded),
+            None => lazy.get().path().into_py_any(py),
+        },
+        Err(_) => Ok(handler.clone_ref(py)),
+    }
+}
+
+/// Whether the registered `handler` is `current`, comparing loaded lazy
+/// references by the function they refer to.
+pub(crate) fn is_handler(py: Python, handler: &PyObject, current: &Bound<'_, PyAny>) -> bool {
+    if handler.is(current) {
+        return true;
+    }
+    match handler.downcast_bound::<LazyHandler>(py) {
+        Ok(lazy) => lazy.get().loaded(py).is_some_and(|h| h.is(current)),
+        Err(_) => false,
+    }
+}
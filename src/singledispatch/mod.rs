@@ -1,5 +1,11 @@
 mod builtins;
+mod combination;
 pub mod core;
+mod entry_points;
+mod lazy;
 mod mro;
+mod typemap;
 mod typeref;
 mod typing;
+#[cfg(not(feature = "abi3"))]
+mod vectorcall;
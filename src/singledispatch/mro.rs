@@ -1,111 +1,404 @@
-use crate::singledispatch::builtins::Builtins;
+use crate::singledispatch::builtins::{Builtins, TypeDescriptor};
 use crate::singledispatch::typeref::PyTypeReference;
 use crate::singledispatch::typing::TypingModule;
+use pyo3::exceptions::PyRuntimeError;
 use pyo3::prelude::*;
-use pyo3::types::PyTuple;
-use pyo3::{intern, Bound, PyObject, PyResult, Python};
+use pyo3::types::{PyString, PyTuple, PyType};
+use pyo3::{intern, Bound, PyResult, PyTraverseError, PyVisit, Python};
 use std::cmp::Reverse;
-use std::collections::hash_map::Keys;
-use std::collections::HashSet;
+use std::collections::{HashSet, VecDeque};
 
-pub(crate) fn get_obj_mro(cls: &Bound<'_, PyAny>) -> PyResult<HashSet<PyTypeReference>> {
-    let mro: HashSet<_> = cls
-        .getattr(intern!(cls.py(), "__mro__"))?
-        .downcast::<PyTuple>()?
-        .iter()
-        .map(|item| PyTypeReference::new(item.unbind()))
-        .collect();
-    Ok(mro)
+/// The items of the attribute `name` of `cls`, which may be any iterable, or
+/// `None` if `cls` does not have the attribute.
+///
+/// The attribute of a class is read from the type object itself through the
+/// C slot of `descriptor`, so that neither a metaclass nor a proxy can
+/// interfere with it. Where that slot can't be read, classes whose metaclass is
+/// `type` can't override the attribute, so it is looked up directly, which is
+/// cheaper than calling `descriptor.__get__`.
+fn attribute_items<'py>(
+    cls: &Bound<'py, PyAny>,
+    name: &Bound<'py, PyString>,
+    descriptor: &TypeDescriptor,
+) -> PyResult<Option<Vec<Bound<'py, PyAny>>>> {
+    let py = cls.py();
+    let value = match cls.downcast::<PyType>() {
+        Ok(typ) => match descriptor.get(typ) {
+            Some(value) => value?,
+            None if cls.get_type().is(py.get_type::<PyType>()) => cls.getattr(name)?,
+            None => descriptor
+                .descriptor
+                .bind(py)
+                .call_method1(intern!(py, "__get__"), (cls,))?,
+        },
+        Err(_) => match cls.getattr_opt(name)? {
+            Some(value) => value,
+            None => return Ok(None),
+        },
+    };
+    if let Ok(items) = value.downcast::<PyTuple>() {
+        return Ok(Some(items.iter().collect()));
+    }
+    Ok(Some(value.try_iter()?.collect::<PyResult<_>>()?))
 }
 
-fn get_obj_subclasses(cls: &Bound<'_, PyAny>) -> PyResult<HashSet<PyTypeReference>> {
-    let subclasses: HashSet<_> = cls
-        .call_method0(intern!(cls.py(), "__subclasses__"))?
-        .downcast::<PyTuple>()?
-        .iter()
-        .map(|item| PyTypeReference::new(item.unbind()))
+/// The MRO of `cls`, in order.
+///
+/// Objects without an MRO, which only support `issubclass` through their
+/// `__bases__`, are treated as if they derived directly from `object`.
+pub(crate) fn get_obj_mro(cls: &Bound<'_, PyAny>) -> PyResult<Vec<PyTypeReference>> {
+    let py = cls.py();
+    let builtins = Builtins::cached(py)?;
+    Ok(
+        match attribute_items(cls, intern!(py, "__mro__"), &builtins.type_mro)? {
+            Some(items) => items
+                .into_iter()
+                .map(|item| PyTypeReference::new(item.unbind()))
+                .collect(),
+            None => Vec::from([
+                PyTypeReference::new(cls.clone().unbind()),
+                PyTypeReference::new(builtins.object_type.clone_ref(py)),
+            ]),
+        },
+    )
+}
+
+fn get_obj_subclasses(cls: &Bound<'_, PyAny>) -> PyResult<Vec<PyTypeReference>> {
+    let py = cls.py();
+    let subclasses = if cls.is_instance_of::<PyType>() {
+        // Look the method up on `type` so that metaclasses such as `type` itself
+        // are asked for their own subclasses rather than binding to the instance.
+        py.get_type::<PyType>()
+            .call_method1(intern!(py, "__subclasses__"), (cls,))?
+    } else {
+        match cls.getattr_opt(intern!(py, "__subclasses__"))? {
+            Some(method) => method.call0()?,
+            None => return Ok(Vec::new()),
+        }
+    };
+    let mut result = Vec::new();
+    for item in subclasses.try_iter()? {
+        result.push(PyTypeReference::new(item?.unbind()));
+    }
+    Ok(result)
+}
+
+/// Classes registered as virtual subclasses of the ABC `cls` via `ABC.register`,
+/// ordered by module and qualified name as the registry itself is a set.
+///
+/// The registry of the C implementation of `abc` is only exposed by its private
+/// `_abc._get_dump` helper. ABCs of the pure Python implementation in `_py_abc`
+/// keep it in their `_abc_registry` attribute instead. If neither is available,
+/// no classes are returned.
+fn get_abc_registry(cls: &Bound<'_, PyAny>) -> PyResult<Vec<PyTypeReference>> {
+    let py = cls.py();
+    if !cls.hasattr(intern!(py, "__abstractmethods__"))? {
+        return Ok(Vec::new());
+    }
+    let dump = py
+        .import(intern!(py, "_abc"))
+        .and_then(|m| m.getattr(intern!(py, "_get_dump")))
+        .and_then(|get_dump| get_dump.call1((cls,)));
+    let mut subclasses = Vec::new();
+    match dump {
+        Ok(dump) => {
+            for weak_ref in dump.get_item(0)?.try_iter()? {
+                subclasses.push(weak_ref?.call0()?);
+            }
+        }
+        Err(_) => {
+            if let Ok(weak_set) = cls.getattr(intern!(py, "_abc_registry")) {
+                for subclass in weak_set.try_iter()? {
+                    subclasses.push(subclass?);
+                }
+            }
+        }
+    }
+    let mut registry = Vec::with_capacity(subclasses.len());
+    for subclass in subclasses {
+        if subclass.is_none() {
+            continue;
+        }
+        let name = (
+            subclass
+                .getattr(intern!(py, "__module__"))?
+                .str()?
+                .to_string(),
+            subclass
+                .getattr(intern!(py, "__qualname__"))?
+                .str()?
+                .to_string(),
+        );
+        registry.push((name, PyTypeReference::new(subclass.unbind())));
+    }
+    registry.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(registry.into_iter().map(|(_, typ)| typ).collect())
+}
+
+/// Return all known subclasses of `types` (excluding `object`), including the
+/// types themselves, classes registered with ABCs and their subclasses.
+///
+/// Classes are returned breadth first, starting with `types` in the order given,
+/// so that the result does not depend on anything but the class hierarchy.
+pub(crate) fn get_known_subclasses<'a>(
+    py: Python,
+    types: impl Iterator<Item = &'a PyTypeReference>,
+) -> PyResult<Vec<PyTypeReference>> {
+    let object_type = &Builtins::cached(py)?.object_type;
+    let mut seen: HashSet<PyTypeReference> = HashSet::new();
+    let mut pending: VecDeque<PyTypeReference> = types
+        .filter(|typ| !typ.wrapped().is(object_type))
+        .map(|typ| typ.clone_ref(py))
         .collect();
-    Ok(subclasses)
+    let mut known = Vec::new();
+    while let Some(typ) = pending.pop_front() {
+        if seen.contains(&typ) {
+            continue;
+        }
+        let cls = typ.wrapped().bind(py);
+        pending.extend(get_obj_subclasses(cls)?);
+        pending.extend(get_abc_registry(cls)?);
+        seen.insert(typ.clone_ref(py));
+        known.push(typ);
+    }
+    Ok(known)
 }
 
-fn c3_mro(
+fn get_obj_bases(cls: &Bound<'_, PyAny>) -> PyResult<Vec<PyTypeReference>> {
+    let py = cls.py();
+    let builtins = Builtins::cached(py)?;
+    Ok(
+        match attribute_items(cls, intern!(py, "__bases__"), &builtins.type_bases)? {
+            Some(items) => items
+                .into_iter()
+                .map(|item| PyTypeReference::new(item.unbind()))
+                .collect(),
+            None => Vec::from([PyTypeReference::new(builtins.object_type.clone_ref(py))]),
+        },
+    )
+}
+
+fn c3_merge(
     py: Python,
-    cls: Bound<'_, PyAny>,
-    abcs: Vec<PyTypeReference>,
+    mut sequences: Vec<Vec<PyTypeReference>>,
 ) -> PyResult<Vec<PyTypeReference>> {
-    Ok(abcs)
+    let mut result: Vec<PyTypeReference> = Vec::new();
+    loop {
+        sequences.retain(|seq| !seq.is_empty());
+        if sequences.is_empty() {
+            return Ok(result);
+        }
+
+        // Find a merge candidate among the sequence heads which does not appear in
+        // the tail of any other sequence.
+        let candidate = sequences
+            .iter()
+            .map(|seq| &seq[0])
+            .find(|&head| !sequences.iter().any(|seq| seq[1..].contains(head)))
+            .map(|head| head.clone_ref(py));
+        let candidate = match candidate {
+            Some(c) => c,
+            None => return Err(PyRuntimeError::new_err("Inconsistent hierarchy")),
+        };
+
+        for seq in sequences.iter_mut() {
+            if seq[0] == candidate {
+                seq.remove(0);
+            }
+        }
+        result.push(candidate);
+    }
 }
 
-pub(crate) fn compose_mro(
+fn c3_mro(
     py: Python,
-    cls: Bound<'_, PyAny>,
-    types: Keys<PyTypeReference, PyObject>,
+    cls: &Bound<'_, PyAny>,
+    abcs: &[PyTypeReference],
 ) -> PyResult<Vec<PyTypeReference>> {
-    let builtins = Builtins::cached(py);
-    let typing = TypingModule::cached(py);
+    let builtins = Builtins::cached(py)?;
+    let bases = get_obj_bases(cls)?;
+
+    // Bases up to the last explicit ABC are considered first.
+    let mut boundary = 0;
+    for (i, base) in bases.iter().enumerate().rev() {
+        if base
+            .wrapped()
+            .bind(py)
+            .hasattr(intern!(py, "__abstractmethods__"))?
+        {
+            boundary = i + 1;
+            break;
+        }
+    }
 
-    let bases: HashSet<_> = get_obj_mro(&cls)?;
-    let registered_types: HashSet<_> = types.collect();
-    let eligible_types: HashSet<_> = registered_types
+    let explicit_bases: Vec<_> = bases[..boundary].iter().map(|b| b.clone_ref(py)).collect();
+    let other_bases: Vec<_> = bases[boundary..].iter().map(|b| b.clone_ref(py)).collect();
+    let mut abstract_bases: Vec<PyTypeReference> = Vec::new();
+    for base in abcs.iter() {
+        // If *cls* is the class that introduces behaviour described by an ABC *base*,
+        // insert said ABC to its MRO.
+        let typ = base.wrapped().bind(py);
+        if builtins.issubclass(py, cls, typ)? {
+            let mut introduced_by_base = false;
+            for b in bases.iter() {
+                if builtins.issubclass(py, b.wrapped().bind(py), typ)? {
+                    introduced_by_base = true;
+                    break;
+                }
+            }
+            if !introduced_by_base {
+                abstract_bases.push(base.clone_ref(py));
+            }
+        }
+    }
+    let abcs: Vec<_> = abcs
         .iter()
-        .filter(|&tref| {
-            // Remove entries which are already present in the __mro__ or unrelated.
-            let typ = tref.wrapped().bind(py);
-            !bases.contains(tref)
-                && typ.hasattr(intern!(py, "__mro__")).unwrap()
-                && !typ
-                    .is_instance(typing.generic_alias_type.wrapped().bind(py))
-                    .unwrap()
-                && builtins.issubclass(py, &cls, typ).unwrap()
-        })
-        .filter(|&tref| {
-            // Remove entries which are strict bases of other entries (they will end up
-            // in the MRO anyway).
-            !registered_types.iter().any(|&other| {
-                let other_mro = get_obj_mro(other.wrapped().bind(py)).unwrap();
-                *tref != other && other_mro.contains(tref)
-            })
+        .filter(|abc| !abstract_bases.contains(abc))
+        .map(|abc| abc.clone_ref(py))
+        .collect();
+
+    let mut sequences = Vec::with_capacity(4 + bases.len() + abstract_bases.len());
+    sequences.push(Vec::from([PyTypeReference::new(cls.clone().unbind())]));
+    for base in explicit_bases
+        .iter()
+        .chain(abstract_bases.iter())
+        .chain(other_bases.iter())
+    {
+        sequences.push(c3_mro(py, base.wrapped().bind(py), &abcs)?);
+    }
+    sequences.push(explicit_bases);
+    sequences.push(abstract_bases);
+    sequences.push(other_bases);
+
+    c3_merge(py, sequences)
+}
+
+/// What `compose_mro` needs to know about a registered type, computed once when
+/// the type is registered so that composing the MRO of a class only calls into
+/// Python for the registered types which may be related to it.
+pub(crate) struct TypeInfo {
+    /// Whether the type can be a virtual base of classes which don't have it in
+    /// their MRO, i.e. whether its metaclass (such as `ABCMeta`) may implement
+    /// `__subclasscheck__`.
+    virtual_subclasses: bool,
+    /// Whether the type can be inserted into an MRO at all.
+    eligible: bool,
+    /// The classes in the MRO of the type, other than the type itself. The MRO
+    /// of a class only changes if its `__bases__` are reassigned.
+    strict_bases: Vec<PyTypeReference>,
+}
+
+impl TypeInfo {
+    pub(crate) fn new(typ: &Bound<'_, PyAny>) -> PyResult<Self> {
+        let py = typ.py();
+        let typing = TypingModule::cached(py)?;
+        Ok(TypeInfo {
+            virtual_subclasses: !typ.get_type().is(py.get_type::<PyType>()),
+            eligible: typ.hasattr(intern!(py, "__mro__"))?
+                && !typ.is_instance(typing.generic_alias_type.wrapped().bind(py))?,
+            strict_bases: get_obj_mro(typ)?.into_iter().skip(1).collect(),
         })
-        .copied()
+    }
+
+    pub(crate) fn clone_ref(&self, py: Python) -> Self {
+        TypeInfo {
+            virtual_subclasses: self.virtual_subclasses,
+            eligible: self.eligible,
+            strict_bases: self.strict_bases.iter().map(|b| b.clone_ref(py)).collect(),
+        }
+    }
+
+    pub(crate) fn traverse(&self, visit: &PyVisit<'_>) -> Result<(), PyTraverseError> {
+        for base in self.strict_bases.iter() {
+            visit.call(base.wrapped())?;
+        }
+        Ok(())
+    }
+}
+
+/// Proxies of a class report the MRO of the class they stand in for as their
+/// own `__mro__`, so they are resolved as the first class in it.
+fn resolve_proxy(cls: Bound<'_, PyAny>) -> PyResult<Bound<'_, PyAny>> {
+    if cls.is_instance_of::<PyType>() {
+        return Ok(cls);
+    }
+    match get_obj_mro(&cls)?.first() {
+        Some(first) if first.wrapped().bind(cls.py()).is_instance_of::<PyType>() => {
+            Ok(first.wrapped().bind(cls.py()).clone())
+        }
+        _ => Ok(cls),
+    }
+}
+
+pub(crate) fn compose_mro<'a>(
+    py: Python,
+    cls: Bound<'_, PyAny>,
+    types: impl Iterator<Item = (&'a PyTypeReference, &'a TypeInfo)>,
+) -> PyResult<Vec<PyTypeReference>> {
+    let builtins = Builtins::cached(py)?;
+    let cls = resolve_proxy(cls)?;
+
+    // Everything iterated over below is kept in a deterministic order (the order
+    // of `types`, of `__subclasses__()` and of `__mro__`), so that the same
+    // registry and hierarchy always compose the same MRO.
+    let bases = get_obj_mro(&cls)?;
+    // A class whose metaclass is `type` is only a base of the classes which have
+    // it in their MRO, so unless `cls` is merely class-like, only the types with
+    // virtual subclasses need to be checked with `issubclass`.
+    let is_class = cls.is_instance_of::<PyType>();
+    // MROs and the related types are short, so plain vectors are cheaper to
+    // search than hash sets are to build.
+    let mut related_types: Vec<&PyTypeReference> = Vec::new();
+    let mut strict_bases: Vec<&PyTypeReference> = Vec::new();
+    for (tref, info) in types {
+        // Remove entries which are already present in the __mro__ or unrelated,
+        // as well as those which `types` repeats (it may chain several tables).
+        if info.eligible
+            && (info.virtual_subclasses || !is_class)
+            && !bases.contains(tref)
+            && !related_types.contains(&tref)
+            && builtins.issubclass(py, &cls, tref.wrapped().bind(py))?
+        {
+            related_types.push(tref);
+            strict_bases.extend(info.strict_bases.iter());
+        }
+    }
+    // Remove entries which are strict bases of other entries (they will end up
+    // in the MRO anyway).
+    let eligible_types: Vec<&PyTypeReference> = related_types
+        .into_iter()
+        .filter(|tref| !strict_bases.contains(tref))
         .collect();
     let mut mro: Vec<PyTypeReference> = Vec::new();
-    eligible_types.iter().for_each(|&tref| {
+    for &tref in eligible_types.iter() {
         // Subclasses of the ABCs in *types* which are also implemented by
         // *cls* can be used to stabilize ABC ordering.
         let typ = tref.wrapped().bind(py);
-        let mut found_subclasses: Vec<_> = get_obj_subclasses(typ)
-            .unwrap()
-            .iter()
-            .filter(|subclass| {
-                let typ = subclass.wrapped();
-                let tref = PyTypeReference::new(typ.clone_ref(py));
-                !bases.contains(&tref)
-                    && Builtins::cached(py)
-                        .issubclass(py, &cls, &typ.clone_ref(py).into_bound(py))
-                        .unwrap()
-            })
-            .map(|subclass| {
-                let obj_mro: Vec<_> = get_obj_mro(subclass.wrapped().bind(py))
-                    .unwrap()
+        let mut found_subclasses: Vec<Vec<PyTypeReference>> = Vec::new();
+        for subclass in get_obj_subclasses(typ)? {
+            let subclass_type = subclass.wrapped().bind(py);
+            if bases.contains(&subclass) || !builtins.issubclass(py, &cls, subclass_type)? {
+                continue;
+            }
+            found_subclasses.push(
+                get_obj_mro(subclass_type)?
                     .into_iter()
-                    .filter(|tref| eligible_types.contains(tref))
-                    .collect();
-
-                obj_mro
-            })
-            .collect();
+                    .filter(|tref| eligible_types.contains(&tref))
+                    .collect(),
+            );
+        }
 
         if found_subclasses.is_empty() {
             mro.push(tref.clone_ref(py));
         } else {
             found_subclasses.sort_by_key(|s| Reverse(s.len()));
-            found_subclasses.iter().flatten().for_each(|tref| {
+            for tref in found_subclasses.iter().flatten() {
                 if !mro.contains(tref) {
                     mro.push(tref.clone_ref(py));
                 }
-            });
+            }
         }
-    });
+    }
 
-    c3_mro(py, cls, mro)
+    c3_mro(py, &cls, &mro)
 }
@@ -0,0 +1,116 @@
+use crate::singledispatch::mro::TypeInfo;
+use crate::singledispatch::typeref::{AddressHash, PyTypeReference};
+use pyo3::{PyObject, PyResult, PyTraverseError, PyVisit, Python};
+use std::collections::HashMap;
+
+/// A map of types to handlers which iterates in insertion order, so that
+/// resolution never depends on the order of a hash table.
+///
+/// Replacing the value of an existing key keeps its original position. Each type
+/// is indexed for `compose_mro` when it is first inserted.
+pub(crate) struct TypeMap {
+    entries: Vec<(PyTypeReference, PyObject, TypeInfo)>,
+    /// Position of each entry, keyed by the address of its type (which is kept
+    /// alive by the entry itself).
+    index: HashMap<usize, usize, AddressHash>,
+}
+
+fn address(typ: &PyTypeReference) -> usize {
+    typ.wrapped().as_ptr() as usize
+}
+
+impl TypeMap {
+    pub(crate) fn new() -> Self {
+        TypeMap {
+            entries: Vec::new(),
+            index: HashMap::default(),
+        }
+    }
+
+    pub(crate) fn clone_ref(&self, py: Python) -> Self {
+        TypeMap {
+            entries: self
+                .entries
+                .iter()
+                .map(|(typ, value, info)| {
+                    (typ.clone_ref(py), value.clone_ref(py), info.clone_ref(py))
+                })
+                .collect(),
+            index: self.index.clone(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub(crate) fn contains_key(&self, typ: &PyTypeReference) -> bool {
+        self.index.contains_key(&address(typ))
+    }
+
+    pub(crate) fn get(&self, typ: &PyTypeReference) -> Option<&PyObject> {
+        self.index.get(&address(typ)).map(|&i| &self.entries[i].1)
+    }
+
+    pub(crate) fn insert(
+        &mut self,
+        py: Python,
+        typ: PyTypeReference,
+        value: PyObject,
+    ) -> PyResult<Option<PyObject>> {
+        if let Some(&i) = self.index.get(&address(&typ)) {
+            return Ok(Some(std::mem::replace(&mut self.entries[i].1, value)));
+        }
+        let info = TypeInfo::new(typ.wrapped().bind(py))?;
+        self.push(typ, value, info);
+        Ok(None)
+    }
+
+    fn push(&mut self, typ: PyTypeReference, value: PyObject, info: TypeInfo) {
+        self.index.insert(address(&typ), self.entries.len());
+        self.entries.push((typ, value, info));
+    }
+
+    /// Insert all entries of `other`, replacing the values of existing keys.
+    pub(crate) fn update(&mut self, py: Python, other: &TypeMap) {
+        for (typ, value, info) in other.entries.iter() {
+            match self.index.get(&address(typ)) {
+                Some(&i) => self.entries[i].1 = value.clone_ref(py),
+                None => self.push(typ.clone_ref(py), value.clone_ref(py), info.clone_ref(py)),
+            }
+        }
+    }
+
+    pub(crate) fn remove(&mut self, typ: &PyTypeReference) -> Option<PyObject> {
+        let i = self.index.remove(&address(typ))?;
+        let (_, value, _) = self.entries.remove(i);
+        for j in self.index.values_mut() {
+            if *j > i {
+                *j -= 1;
+            }
+        }
+        Some(value)
+    }
+
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &PyTypeReference> {
+        self.entries.iter().map(|(typ, _, _)| typ)
+    }
+
+    /// The keys along with their index for `compose_mro`.
+    pub(crate) fn indexed(&self) -> impl Iterator<Item = (&PyTypeReference, &TypeInfo)> {
+        self.entries.iter().map(|(typ, _, info)| (typ, info))
+    }
+
+    pub(crate) fn traverse(&self, visit: &PyVisit<'_>) -> Result<(), PyTraverseError> {
+        for (typ, value, info) in self.entries.iter() {
+            visit.call(typ.wrapped())?;
+            visit.call(value)?;
+            info.traverse(visit)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&PyTypeReference, &PyObject)> {
+        self.entries.iter().map(|(typ, value, _)| (typ, value))
+    }
+}
@@ -1,6 +1,6 @@
 use pyo3::{PyObject, Python};
 use std::fmt::{Display, Formatter};
-use std::hash::{Hash, Hasher};
+use std::hash::{BuildHasherDefault, Hash, Hasher};
 
 pub struct PyTypeReference {
     wrapped: PyObject,
@@ -41,3 +41,31 @@ impl PartialEq for PyTypeReference {
 }
 
 impl Eq for PyTypeReference {}
+
+/// A hasher for keys made of object addresses, which are already unique and
+/// only need to be mixed, rather than hashed in a DoS-resistant way.
+#[derive(Default)]
+pub(crate) struct AddressHasher(u64);
+
+impl Hasher for AddressHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_u64(byte as u64);
+        }
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = (self.0.rotate_left(5) ^ n).wrapping_mul(0x51_7c_c1_b7_27_22_0a_95);
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        self.write_u64(n as u64);
+    }
+}
+
+/// Build hasher for maps keyed by [`PyTypeReference`]s or object addresses.
+pub(crate) type AddressHash = BuildHasherDefault<AddressHasher>;
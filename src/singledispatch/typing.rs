@@ -2,64 +2,71 @@ use crate::singledispatch::typeref::PyTypeReference;
 use pyo3::exceptions::PyTypeError;
 use pyo3::prelude::*;
 use pyo3::sync::GILOnceCell;
-use pyo3::types::PyTuple;
-use pyo3::{Bound, IntoPyObjectExt, Py, PyAny, PyObject, PyResult, Python};
+use pyo3::types::{PyTuple, PyType};
+use pyo3::{intern, Bound, IntoPyObjectExt, Py, PyAny, PyObject, PyResult, Python};
 
+/// The parts of `typing` that dispatch relies on. Features missing from older
+/// versions of Python are detected when the module is first used.
 pub struct TypingModule {
     get_origin: PyObject,
     get_args: PyObject,
+    get_type_hints: PyObject,
+    /// `typing.get_overloads` on Python 3.11+.
+    get_overloads: Option<PyObject>,
+    any_type: PyObject,
+    mapping_type: PyObject,
+    collection_type: PyObject,
     pub generic_alias_type: PyTypeReference,
+    /// `typing.Union`, and `types.UnionType` (the type of `X | Y`) on Python 3.10+.
     union_types: Vec<PyTypeReference>,
+    /// `typing.TypeAliasType` (the type of `type X = ...` aliases) on Python 3.12+.
+    type_alias_type: Option<PyObject>,
+}
+
+/// The attribute `name` of `module`, or `None` if this version of Python does
+/// not provide it.
+fn optional_attr(module: &Bound<'_, PyModule>, name: &str) -> PyResult<Option<PyObject>> {
+    module
+        .getattr_opt(name)?
+        .map(|attr| attr.into_py_any(module.py()))
+        .transpose()
 }
 
 static TYPING_MODULE: GILOnceCell<TypingModule> = GILOnceCell::new();
 
 impl TypingModule {
-    fn new(py: Python) -> Self {
-        let typing_module = py.import("typing").unwrap();
-        let types_module = py.import("types").unwrap();
+    fn new(py: Python) -> PyResult<Self> {
+        let typing_module = py.import("typing")?;
+        let types_module = py.import("types")?;
+        let collections_abc_module = py.import("collections.abc")?;
         let mut union_types = Vec::with_capacity(2);
-        union_types.extend([
-            PyTypeReference::new(
-                typing_module
-                    .getattr("Union")
-                    .unwrap()
-                    .into_py_any(py)
-                    .unwrap(),
-            ),
-            PyTypeReference::new(
-                types_module
-                    .getattr("UnionType")
-                    .unwrap()
-                    .into_py_any(py)
-                    .unwrap(),
-            ),
-        ]);
+        union_types.push(PyTypeReference::new(
+            typing_module.getattr("Union")?.into_py_any(py)?,
+        ));
+        if let Some(union_type) = optional_attr(&types_module, "UnionType")? {
+            union_types.push(PyTypeReference::new(union_type));
+        }
 
-        TypingModule {
-            get_args: typing_module
-                .getattr("get_args")
-                .unwrap()
-                .into_py_any(py)
-                .unwrap(),
-            get_origin: typing_module
-                .getattr("get_origin")
-                .unwrap()
-                .into_py_any(py)
-                .unwrap(),
+        Ok(TypingModule {
+            get_args: typing_module.getattr("get_args")?.into_py_any(py)?,
+            get_origin: typing_module.getattr("get_origin")?.into_py_any(py)?,
+            get_type_hints: typing_module.getattr("get_type_hints")?.into_py_any(py)?,
+            get_overloads: optional_attr(&typing_module, "get_overloads")?,
+            any_type: typing_module.getattr("Any")?.into_py_any(py)?,
+            mapping_type: collections_abc_module.getattr("Mapping")?.into_py_any(py)?,
+            collection_type: collections_abc_module
+                .getattr("Collection")?
+                .into_py_any(py)?,
             generic_alias_type: PyTypeReference::new(
-                types_module
-                    .getattr("GenericAlias")
-                    .unwrap()
-                    .into_py_any(py)
-                    .unwrap(),
+                types_module.getattr("GenericAlias")?.into_py_any(py)?,
             ),
             union_types,
-        }
+            type_alias_type: optional_attr(&typing_module, "TypeAliasType")?,
+        })
     }
 
-    pub fn cached(py: Python) -> &Self {
-        TYPING_MODULE.get_or_init(py, || TypingModule::new(py))
+    pub fn cached(py: Python<'_>) -> PyResult<&Self> {
+        TYPING_MODULE.get_or_try_init(py, || TypingModule::new(py))
     }
 
     pub fn get_args(&self, py: Python, cls: &Bound<'_, PyAny>) -> PyResult<Py<PyTuple>> {
@@ -76,8 +83,153 @@ impl TypingModule {
         self.get_origin.call1(py, PyTuple::new(py, [cls])?)
     }
 
+    /// The annotations of the first parameter of each `@typing.overload` signature
+    /// of `func`, or `None` if it has none, or this version of Python does not
+    /// record overloads.
+    pub fn overload_annotations<'py>(
+        &self,
+        py: Python<'py>,
+        func: &Bound<'py, PyAny>,
+    ) -> PyResult<Option<Vec<Bound<'py, PyAny>>>> {
+        let get_overloads = match &self.get_overloads {
+            Some(get_overloads) if func.is_callable() => get_overloads.bind(py),
+            _ => return Ok(None),
+        };
+        let mut annotations = Vec::new();
+        for overload in get_overloads.call1((func,))?.try_iter()? {
+            let overload = overload?;
+            let code = overload.getattr(intern!(py, "__code__"))?;
+            let parameter = match code
+                .getattr(intern!(py, "co_argcount"))?
+                .extract::<usize>()?
+            {
+                0 => None,
+                _ => Some(code.getattr(intern!(py, "co_varnames"))?.get_item(0)?),
+            };
+            let hints = self.get_type_hints.bind(py).call1((&overload,))?;
+            let Some(Ok(annotation)) = parameter.map(|name| hints.get_item(&name)) else {
+                return Err(PyTypeError::new_err(format!(
+                    "Invalid overload {overload} of {func}: its first parameter is not annotated."
+                )));
+            };
+            annotations.push(annotation);
+        }
+        Ok(if annotations.is_empty() {
+            None
+        } else {
+            Some(annotations)
+        })
+    }
+
+    /// Resolve `type X = ...` aliases to the value they stand for. Anything else
+    /// is returned as is.
+    pub fn resolve_type_alias<'py>(
+        &self,
+        py: Python<'py>,
+        cls: &Bound<'py, PyAny>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let mut cls = cls.clone();
+        if let Some(type_alias_type) = &self.type_alias_type {
+            while cls.is_instance(type_alias_type.bind(py))? {
+                cls = cls.getattr(intern!(py, "__value__"))?;
+            }
+        }
+        Ok(cls)
+    }
+
     pub fn is_union_type(&self, py: Python, cls: &Bound<'_, PyAny>) -> PyResult<bool> {
         let origin_type_reference = PyTypeReference::new(cls.into_py_any(py)?);
         Ok(self.union_types.contains(&origin_type_reference))
     }
+
+    /// Check whether `obj` is an instance of the (possibly parameterized) `annotation`.
+    ///
+    /// Element types are only checked for mappings, tuples and other collections,
+    /// which can be iterated without being consumed. Annotations which cannot be
+    /// checked at runtime (such as type variables) always match.
+    pub fn is_instance(
+        &self,
+        py: Python,
+        obj: &Bound<'_, PyAny>,
+        annotation: &Bound<'_, PyAny>,
+    ) -> PyResult<bool> {
+        let annotation = &self.resolve_type_alias(py, annotation)?;
+        if annotation.is(&self.any_type) {
+            return Ok(true);
+        }
+        let origin = self.get_origin(py, annotation)?;
+        let origin = origin.bind(py);
+        if origin.is_none() {
+            return match annotation.downcast::<PyType>() {
+                Ok(typ) => obj.is_instance(typ),
+                Err(_) => Ok(true),
+            };
+        }
+
+        let args = self.get_args(py, annotation)?;
+        let args = args.bind(py);
+        if self.is_union_type(py, origin)? {
+            for arg in args.iter() {
+                if self.is_instance(py, obj, &arg)? {
+                    return Ok(true);
+                }
+            }
+            return Ok(false);
+        }
+
+        let origin = match origin.downcast::<PyType>() {
+            Ok(typ) => typ,
+            Err(_) => return Ok(true),
+        };
+        if !obj.is_instance(origin)? {
+            return Ok(false);
+        }
+        if args.is_empty() {
+            return Ok(true);
+        }
+
+        if origin.is_subclass(self.mapping_type.bind(py))? && args.len() == 2 {
+            let key_type = args.get_item(0)?;
+            let value_type = args.get_item(1)?;
+            for item in obj.call_method0(intern!(py, "items"))?.try_iter()? {
+                let (key, value) = item?.extract::<(Bound<'_, PyAny>, Bound<'_, PyAny>)>()?;
+                if !self.is_instance(py, &key, &key_type)?
+                    || !self.is_instance(py, &value, &value_type)?
+                {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        } else if origin.is_subclass_of::<PyTuple>()? {
+            let items = obj.downcast::<PyTuple>()?;
+            if args.len() == 2 && args.get_item(1)?.is(py.Ellipsis()) {
+                let item_type = args.get_item(0)?;
+                for item in items.iter() {
+                    if !self.is_instance(py, &item, &item_type)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            } else if items.len() != args.len() {
+                Ok(false)
+            } else {
+                for (item, item_type) in items.iter().zip(args.iter()) {
+                    if !self.is_instance(py, &item, &item_type)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+        } else if origin.is_subclass(self.collection_type.bind(py))? && args.len() == 1 {
+            let item_type = args.get_item(0)?;
+            for item in obj.try_iter()? {
+                if !self.is_instance(py, &item?, &item_type)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        } else {
+            Ok(true)
+        }
+    }
 }
@@ -0,0 +1,82 @@
+//! The vectorcall protocol for dispatchers, which is only available outside of
+//! the stable ABI.
+//!
+//! PyO3 has no way to declare vectorcall for a class, so the slot is installed
+//! on the `SingleDispatch` type when the first dispatcher is created: every
+//! dispatcher keeps a pointer to [`call`] in its `vectorcall` field, whose
+//! offset in the object is then stored as the `tp_vectorcall_offset` of the
+//! type. Calls then reach the handler with the arguments exactly as the caller
+//! passed them, without a tuple or dict being built for `__call__`.
+
+use crate::singledispatch::core::{missing_argument, SingleDispatch};
+use pyo3::ffi;
+use pyo3::impl_::trampoline::fastcall_with_keywords;
+use pyo3::prelude::*;
+use pyo3::sync::GILOnceCell;
+
+static ENABLED: GILOnceCell<()> = GILOnceCell::new();
+
+/// Install the vectorcall slot on the type of `dispatcher`, unless it is
+/// already installed.
+pub(crate) fn enable(dispatcher: &Bound<'_, SingleDispatch>) -> PyResult<()> {
+    ENABLED
+        .get_or_try_init(dispatcher.py(), || {
+            let field = std::ptr::from_ref(&dispatcher.try_borrow()?.vectorcall);
+            let offset = field as isize - dispatcher.as_ptr() as isize;
+            // SAFETY: the GIL is held, and every dispatcher stores `call` at the
+            // same offset, as they are all created by `SingleDispatch::new`.
+            unsafe {
+                let typ = ffi::Py_TYPE(dispatcher.as_ptr());
+                (*typ).tp_vectorcall_offset = offset;
+                (*typ).tp_flags |= ffi::Py_TPFLAGS_HAVE_VECTORCALL;
+                ffi::PyType_Modified(typ);
+            }
+            Ok(())
+        })
+        .map(|_| ())
+}
+
+/// The `vectorcallfunc` of dispatchers, which calls the handler for the first
+/// argument with the arguments given.
+pub(crate) unsafe extern "C" fn call(
+    callable: *mut ffi::PyObject,
+    args: *const *mut ffi::PyObject,
+    nargsf: usize,
+    kwnames: *mut ffi::PyObject,
+) -> *mut ffi::PyObject {
+    // This is the trampoline of PyO3's own `METH_FASTCALL` methods, which traps
+    // panics and, unlike `Python::with_gil`, relies on the GIL being held rather
+    // than ensuring it. The flags in `nargsf` are passed through as they are.
+    fastcall_with_keywords(
+        callable,
+        args,
+        nargsf as ffi::Py_ssize_t,
+        kwnames,
+        call_handler,
+    )
+}
+
+unsafe fn call_handler(
+    py: Python,
+    callable: *mut ffi::PyObject,
+    args: *const *mut ffi::PyObject,
+    nargsf: ffi::Py_ssize_t,
+    kwnames: *mut ffi::PyObject,
+) -> PyResult<*mut ffi::PyObject> {
+    let nargsf = nargsf as usize;
+    if ffi::PyVectorcall_NARGS(nargsf) == 0 {
+        return Err(missing_argument());
+    }
+    // `callable` is a dispatcher, since the slot is only installed on its type.
+    let dispatcher = Bound::from_borrowed_ptr(py, callable);
+    let dispatcher = dispatcher.downcast_unchecked::<SingleDispatch>();
+    let obj = Bound::from_borrowed_ptr(py, *args);
+    let handler = dispatcher.try_borrow()?.handler_for(py, &obj)?;
+    // The handler may also use the slot before the arguments, if the caller
+    // allowed it with `PY_VECTORCALL_ARGUMENTS_OFFSET`.
+    let result = ffi::PyObject_Vectorcall(handler.as_ptr(), args, nargsf, kwnames);
+    if result.is_null() {
+        return Err(PyErr::fetch(py));
+    }
+    Ok(result)
+}
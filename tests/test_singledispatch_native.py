@@ -1,7 +1,15 @@
+import collections.abc
+import contextvars
+import gc
+import numbers
+import sys
+import types
+import weakref
+
 import pytest
 from singledispatch_native import singledispatch
 
-from typing import Any
+from typing import Any, Union
 
 @singledispatch
 def some_fun(o: Any) -> str:
@@ -24,8 +32,1510 @@ def _some_fun_str(o: int) -> str:
         (None, "Got None <class 'NoneType'>"),
         ("val", "It's a string!"),
         (1, "It's an int!"),
-        # (True, "It's an int!"),
+        (True, "It's an int!"),
     ]
 )
 def test_singledispatch(v, ret):
     assert some_fun(v) == ret
+
+
+class Base:
+    pass
+
+
+class Middle(Base):
+    pass
+
+
+class Leaf(Middle):
+    pass
+
+
+@singledispatch
+def describe(o: Any) -> list[str]:
+    return ["object"]
+
+
+@describe.register(Base)
+def _describe_base(o: Base) -> list[str]:
+    return ["Base", *describe.next_handler(type(o), _describe_base)(o)]
+
+
+@describe.register(Leaf)
+def _describe_leaf(o: Leaf) -> list[str]:
+    return ["Leaf", *describe.next_handler(type(o), _describe_leaf)(o)]
+
+
+@pytest.mark.parametrize(
+    "v,ret",
+    [
+        (object(), ["object"]),
+        (Base(), ["Base", "object"]),
+        (Middle(), ["Base", "object"]),
+        (Leaf(), ["Leaf", "Base", "object"]),
+    ]
+)
+def test_next_handler(v, ret):
+    assert describe(v) == ret
+
+
+def test_next_handler_is_cached():
+    assert describe.next_handler(Leaf, _describe_leaf) is _describe_base
+    assert describe.next_handler(Leaf, _describe_leaf) is _describe_base
+
+
+def test_next_handler_after_default():
+    default = describe.next_handler(Base, _describe_base)
+    with pytest.raises(RuntimeError, match="No next dispatch function"):
+        describe.next_handler(Base, default)
+
+
+def test_next_handler_not_applicable():
+    with pytest.raises(RuntimeError, match="is not a dispatch function"):
+        describe.next_handler(Middle, _describe_leaf)
+
+
+calls = []
+
+
+@singledispatch
+def combined(o: Any) -> str:
+    calls.append("primary object")
+    return "object"
+
+
+@combined.register(Middle)
+def _combined_middle(o: Middle) -> str:
+    calls.append("primary Middle")
+    return "Middle"
+
+
+@combined.register_before(Base)
+def _combined_before_base(o: Base) -> None:
+    calls.append("before Base")
+
+
+@combined.register_before(Leaf)
+def _combined_before_leaf(o: Leaf) -> None:
+    calls.append("before Leaf")
+
+
+@combined.register_after(Base)
+def _combined_after_base(o: Base) -> None:
+    calls.append("after Base")
+
+
+@combined.register_after(Leaf)
+def _combined_after_leaf(o: Leaf) -> None:
+    calls.append("after Leaf")
+
+
+@combined.register_around(Middle)
+def _combined_around_middle(next_method, o: Middle) -> str:
+    calls.append("around Middle")
+    return f"<{next_method(o)}>"
+
+
+@pytest.mark.parametrize(
+    "v,ret,expected_calls",
+    [
+        (object(), "object", ["primary object"]),
+        (Base(), "object", ["before Base", "primary object", "after Base"]),
+        (
+            Middle(),
+            "<Middle>",
+            ["around Middle", "before Base", "primary Middle", "after Base"],
+        ),
+        (
+            Leaf(),
+            "<Middle>",
+            [
+                "around Middle",
+                "before Leaf",
+                "before Base",
+                "primary Middle",
+                "after Base",
+                "after Leaf",
+            ],
+        ),
+    ]
+)
+def test_method_combination(v, ret, expected_calls):
+    calls.clear()
+    assert combined(v) == ret
+    assert calls == expected_calls
+
+
+@pytest.mark.parametrize("register", ["register_before", "register_after", "register_around"])
+def test_method_combination_abc(register):
+    import abc
+
+    class Thing(abc.ABC):
+        pass
+
+    class C:
+        pass
+
+    Thing.register(C)
+    f = singledispatch(lambda o: "object")
+    f.register(Thing, lambda o: "thing")
+    if register == "register_around":
+        getattr(f, register)(Thing, lambda next_method, o: next_method(o))
+    else:
+        getattr(f, register)(Thing, lambda o: None)
+    assert f(C()) == "thing"
+
+
+def test_effective_method_is_cached():
+    assert combined.dispatch(Leaf) is combined.dispatch(Leaf)
+    assert combined.dispatch(object) is combined.next_handler(Middle, _combined_middle)
+
+
+def test_derive():
+    @singledispatch
+    def parent(o: Any) -> str:
+        return "parent object"
+
+    @parent.register(int)
+    def _parent_int(o: int) -> str:
+        return "parent int"
+
+    child = parent.derive()
+
+    @child.register(str)
+    def _child_str(o: str) -> str:
+        return "child str"
+
+    @child.register(bool)
+    def _child_bool(o: bool) -> str:
+        return "child bool"
+
+    assert child(None) == "parent object"
+    assert child(1) == "parent int"
+    assert child(True) == "child bool"
+    assert child("a") == "child str"
+
+    assert parent(True) == "parent int"
+    assert parent("a") == "parent object"
+
+
+def test_derive_invalidated_by_parent():
+    @singledispatch
+    def parent(o: Any) -> str:
+        return "parent object"
+
+    child = parent.derive()
+    grandchild = child.derive()
+    assert grandchild(1.0) == "parent object"
+
+    @parent.register(float)
+    def _parent_float(o: float) -> str:
+        return "parent float"
+
+    assert child(1.0) == "parent float"
+    assert grandchild(1.0) == "parent float"
+
+    @child.register(float)
+    def _child_float(o: float) -> str:
+        return "child float"
+
+    assert parent(1.0) == "parent float"
+    assert grandchild(1.0) == "child float"
+
+
+def test_override():
+    @singledispatch
+    def f(o: Any) -> str:
+        return "object"
+
+    @f.register(int)
+    def _f_int(o: int) -> str:
+        return "int"
+
+    assert f(True) == "int"
+    with f.override(int, lambda o: "overridden int"):
+        assert f(1) == "overridden int"
+        assert f(True) == "overridden int"
+        with f.override(bool, lambda o: "overridden bool"):
+            assert f(1) == "overridden int"
+            assert f(True) == "overridden bool"
+        assert f(True) == "overridden int"
+    assert f(1) == "int"
+    assert f(True) == "int"
+    assert f.dispatch(int) is _f_int
+
+
+def test_override_lazy_handler():
+    import json
+
+    @singledispatch
+    def f(o: Any) -> str:
+        return "object"
+
+    with f.override(dict, "json:dumps") as handler:
+        assert handler == "json:dumps"
+        assert f({"a": 1}) == '{"a": 1}'
+    assert f({}) == "object"
+
+
+def test_override_derived():
+    @singledispatch
+    def f(o: Any) -> str:
+        return "object"
+
+    f.register(int, lambda o: "int")
+    g = f.derive()
+    g.register(bool, lambda o: "derived bool")
+    h = g.derive()
+
+    assert h(1) == "int"
+    with f.override(int, lambda o: "overridden int"):
+        assert g(1) == "overridden int"
+        assert h(1) == "overridden int"
+        assert h(True) == "derived bool"
+        with g.override(str, lambda o: "overridden str"):
+            assert h(1) == "overridden int"
+            assert h("a") == "overridden str"
+            assert f("a") == "object"
+        with h.override(bool, lambda o: "overridden bool"):
+            assert h(True) == "overridden bool"
+            assert g(True) == "derived bool"
+        assert h("a") == "object"
+        f.register(float, lambda o: "float")
+        assert h(1.0) == "float"
+    assert g(1) == "int"
+    assert h(1) == "int"
+
+
+def test_override_is_context_local():
+    import asyncio
+    import contextvars
+    import threading
+
+    @singledispatch
+    def f(o: Any) -> str:
+        return "object"
+
+    async def in_task() -> str:
+        return f(1)
+
+    results = {}
+    with f.override(int, lambda o: "overridden int"):
+        results["task"] = asyncio.run(in_task())
+
+        context = contextvars.copy_context()
+        copied = threading.Thread(target=lambda: results.update(copied=context.run(f, 1)))
+        copied.start()
+        copied.join()
+
+        plain = threading.Thread(target=lambda: results.update(plain=f(1)))
+        plain.start()
+        plain.join()
+
+    assert results == {
+        "task": "overridden int",
+        "copied": "overridden int",
+        "plain": "object",
+    }
+
+
+def test_override_outlives_block():
+    import contextvars
+
+    @singledispatch
+    def f(o: Any) -> str:
+        return "object"
+
+    f.register(int, lambda o: "int")
+    assert f(1) == "int"
+    with f.override(int, lambda o: "overridden int"):
+        context = contextvars.copy_context()
+    assert f(1) == "int"
+    assert context.run(f, 1) == "overridden int"
+    del context
+    assert f(1) == "int"
+
+
+@singledispatch
+def generic(o: Any) -> str:
+    return "object"
+
+
+@generic.register(list[int])
+def _generic_list(o: list[int]) -> str:
+    return "list"
+
+
+@generic.register(dict[str, Any])
+def _generic_dict(o: dict[str, Any]) -> str:
+    return "dict"
+
+
+@pytest.mark.parametrize(
+    "v,ret",
+    [
+        ([1, 2], "list"),
+        (["a"], "list"),
+        ({"a": 1}, "dict"),
+        ((1, 2), "object"),
+    ]
+)
+def test_parameterized_generics(v, ret):
+    assert generic(v) == ret
+
+
+def test_parameterized_generics_registry():
+    assert generic.registry[list[int]] is _generic_list
+    assert generic.registry[dict[str, Any]] is _generic_dict
+    assert list not in generic.registry
+
+
+def test_parameterized_generics_conflict():
+    @singledispatch
+    def f(o: Any) -> str:
+        return "object"
+
+    _f_list = f.register(list[int], lambda o: "list")
+    with pytest.raises(TypeError, match=r"list\[str\] conflicts with list\[int\]"):
+        f.register(list[str], lambda o: "list of str")
+    with pytest.raises(TypeError, match=r"list\[str\] conflicts with list\[int\]"):
+        f.register(Union[set[int], list[str]], lambda o: "set or list of str")
+    with pytest.raises(TypeError, match=r"tuple\[str\] conflicts with tuple\[int\]"):
+        f.register(Union[tuple[int], tuple[str]], lambda o: "tuple")
+    assert f.registry.keys() == {object, list[int]}
+    assert f.registry[list[int]] is _f_list
+
+    f.register(list[int], lambda o: "list again")
+    assert f([1]) == "list again"
+
+    g = f.derive()
+    g.register(list[str], lambda o: "derived list")
+    assert g(["a"]) == "derived list"
+
+
+def _validated(o: Any) -> str:
+    return "object"
+
+
+validated = singledispatch(_validated, validate_generics=True)
+
+
+@validated.register(list[int])
+def _validated_list(o: list[int]) -> str:
+    return "list"
+
+
+@validated.register(Union[tuple[int, ...], dict[str, list[int]]])
+def _validated_other(o) -> str:
+    return "other"
+
+
+@pytest.mark.parametrize(
+    "v,ret",
+    [
+        ([], "list"),
+        ([1, 2], "list"),
+        ((1, 2, 3), "other"),
+        ({"a": [1]}, "other"),
+        ("abc", "object"),
+    ]
+)
+def test_validate_generics(v, ret):
+    assert validated(v) == ret
+
+
+@pytest.mark.parametrize("v", [["a"], (1, "a"), {"a": ["b"]}, {1: [1]}])
+def test_validate_generics_mismatch(v):
+    with pytest.raises(TypeError, match="does not match registered type"):
+        validated(v)
+
+
+@singledispatch
+def factory(o: Any) -> str:
+    return "object"
+
+
+@factory.register(type)
+def _factory_type(o: type) -> str:
+    return "type"
+
+
+@factory.register(type[Base])
+def _factory_base_class(o: type[Base]) -> str:
+    return f"class {o.__name__}"
+
+
+@factory.register(type[Leaf])
+def _factory_leaf_class(o: type[Leaf]) -> str:
+    return "leaf class"
+
+
+@pytest.mark.parametrize(
+    "v,ret",
+    [
+        (Base, "class Base"),
+        (Middle, "class Middle"),
+        (Leaf, "leaf class"),
+        (int, "type"),
+        (Base(), "object"),
+    ]
+)
+def test_class_object_dispatch(v, ret):
+    assert factory(v) == ret
+
+
+def test_class_object_registry():
+    assert factory.registry[type[Base]] is _factory_base_class
+    assert factory.registry[type] is _factory_type
+
+
+def test_lazy_type_name():
+    import sys
+
+    @singledispatch
+    def f(o: Any) -> str:
+        return "object"
+
+    @f.register("fractions.Fraction")
+    def _f_fraction(o) -> str:
+        return "fraction"
+
+    f.register(f"{__name__}.Middle", lambda o: "middle")
+
+    assert f.registry["fractions.Fraction"] is _f_fraction
+    assert f(Leaf()) == "middle"
+    assert f"{__name__}.Middle" not in f.registry
+    assert f.registry[Middle](Middle()) == "middle"
+
+    sys.modules.pop("fractions", None)
+    assert f(1) == "object"
+    assert "fractions.Fraction" in f.registry
+
+    from fractions import Fraction
+
+    assert f(Fraction(1, 2)) == "fraction"
+    assert f.registry[Fraction] is _f_fraction
+
+
+def test_lazy_type_name_invalid():
+    @singledispatch
+    def f(o: Any) -> str:
+        return "object"
+
+    with pytest.raises(TypeError, match="dotted type name"):
+        f.register("Fraction", lambda o: "fraction")
+
+    f.register("sys.path", lambda o: "not a class")
+    f.register("fractions.Fraction", lambda o: "fraction")
+    f.register(str, lambda o: "str")
+    with pytest.warns(RuntimeWarning, match="'sys.path' does not name a class, but a 'list' object"):
+        assert f(1) == "object"
+    assert f("a") == "str"
+    assert "sys.path" not in f.registry
+
+    from fractions import Fraction
+
+    assert f(Fraction(1, 2)) == "fraction"
+
+
+def test_lazy_type_name_imported_later(monkeypatch):
+    import abc
+
+    @singledispatch
+    def f(o: Any) -> str:
+        return "object"
+
+    f.register("lazy_abc_module.Things", lambda o: "things")
+    assert f(1) == "object"
+
+    class Things(abc.ABC):
+        pass
+
+    Things.register(int)
+    module = types.ModuleType("lazy_abc_module")
+    module.Things = Things
+    monkeypatch.setitem(sys.modules, "lazy_abc_module", module)
+    assert f(1) == "things"
+    assert f.registry[Things](1) == "things"
+
+
+def test_lazy_type_name_reentrant(monkeypatch):
+    @singledispatch
+    def f(o: Any) -> str:
+        return "object"
+
+    module = types.ModuleType("reentrant_types_module")
+
+    def __getattr__(name):
+        # Looking up the name registers another handler on the dispatcher.
+        f.register(str, lambda o: "str")
+        if name == "Integer":
+            return int
+        raise AttributeError(name)
+
+    module.__getattr__ = __getattr__
+    monkeypatch.setitem(sys.modules, "reentrant_types_module", module)
+    f.register("reentrant_types_module.Integer", lambda o: "integer")
+    assert f(1) == "integer"
+    assert f("a") == "str"
+
+
+def test_lazy_handler():
+    import json
+
+    @singledispatch
+    def f(o: Any) -> str:
+        return "object"
+
+    f.register(dict, "json:dumps")
+    assert f.registry[dict] == "json:dumps"
+
+    assert f({"a": 1}) == '{"a": 1}'
+    assert f.registry[dict] is json.dumps
+    assert f.next_handler(dict, json.dumps) is f.registry[object]
+
+
+@pytest.mark.parametrize(
+    "path",
+    ["singledispatch_native_missing_module:f", "json:singledispatch_native_missing"],
+)
+def test_lazy_handler_import_error(path):
+    @singledispatch
+    def f(o: Any) -> str:
+        return "object"
+
+    f.register(int, path)
+    with pytest.raises(RuntimeError, match=f"Could not load dispatch function '{path}'") as exc_info:
+        f(1)
+    assert isinstance(exc_info.value.__cause__, (ImportError, AttributeError))
+    assert f.registry[int] == path
+
+
+def test_lazy_handler_invalid():
+    @singledispatch
+    def f(o: Any) -> str:
+        return "object"
+
+    with pytest.raises(TypeError, match="module:function"):
+        f.register(int, "json.dumps")
+
+
+def test_lazy_handler_registers_on_import(tmp_path, monkeypatch):
+    (tmp_path / "sdn_registering_plugin.py").write_text(
+        "from sdn_registering_host import f\n\n"
+        "f.register(float, lambda o: 'float')\n\n"
+        "def handle_int(o):\n    return f.dispatch(float)(o) + ' via int'\n"
+    )
+    monkeypatch.syspath_prepend(tmp_path)
+
+    @singledispatch
+    def f(o: Any) -> str:
+        return "object"
+
+    host = types.ModuleType("sdn_registering_host")
+    host.f = f
+    monkeypatch.setitem(sys.modules, "sdn_registering_host", host)
+    monkeypatch.delitem(sys.modules, "sdn_registering_plugin", raising=False)
+
+    f.register(int, "sdn_registering_plugin:handle_int")
+    assert f(1) == "float via int"
+    assert f(1.0) == "float"
+    assert f("a") == "object"
+
+
+def _ambiguous_abcs():
+    import abc
+
+    class Readable(abc.ABC):
+        pass
+
+    class Writable(abc.ABC):
+        pass
+
+    class Stream:
+        pass
+
+    Readable.register(Stream)
+    Writable.register(Stream)
+
+    @singledispatch
+    def f(o: Any) -> str:
+        return "object"
+
+    return f, Readable, Writable, Stream
+
+
+def test_check_ambiguities():
+    f, Readable, Writable, Stream = _ambiguous_abcs()
+    assert f.check() == []
+
+    f.register(Readable, lambda o: "readable")
+    f.register(Writable, lambda o: "writable")
+
+    ((cls, first, second),) = f.check()
+    assert cls is Stream
+    assert {first, second} == {Readable, Writable}
+    assert f.check(classes=[int, Stream])[0][0] is Stream
+    assert f.check(classes=[int]) == []
+
+    f.register(Stream, lambda o: "stream")
+    assert f.check() == []
+
+    with pytest.raises(TypeError, match="not a class"):
+        f.check(classes=[1])
+
+
+def test_check_ambiguities_py_abc():
+    import _py_abc
+
+    class Readable(metaclass=_py_abc.ABCMeta):
+        pass
+
+    class Writable(metaclass=_py_abc.ABCMeta):
+        pass
+
+    @Readable.register
+    @Writable.register
+    class Stream:
+        pass
+
+    @singledispatch
+    def f(o: Any) -> str:
+        return "object"
+
+    f.register(Readable, lambda o: "readable")
+    f.register(Writable, lambda o: "writable")
+    assert [cls for cls, _, _ in f.check()] == [Stream]
+
+
+def test_check_ambiguities_order():
+    import abc
+
+    class Readable(abc.ABC):
+        pass
+
+    class Writable(abc.ABC):
+        pass
+
+    class Zeta:
+        pass
+
+    class Alpha:
+        pass
+
+    class ZetaChild(Zeta):
+        pass
+
+    for cls in (Zeta, Alpha):
+        Writable.register(cls)
+        Readable.register(cls)
+
+    @singledispatch
+    def f(o: Any) -> str:
+        return "object"
+
+    f.register(Readable, lambda o: "readable")
+    f.register(Writable, lambda o: "writable")
+    assert [cls for cls, _, _ in f.check()] == [Alpha, Zeta, ZetaChild]
+
+
+def test_register_strict():
+    f, Readable, Writable, Stream = _ambiguous_abcs()
+    f.register(Readable, lambda o: "readable", strict=True)
+
+    with pytest.raises(RuntimeError, match="Ambiguous dispatch for .*Stream"):
+        f.register(Writable, strict=True)(lambda o: "writable")
+    assert Writable not in f.registry
+    assert f(Stream()) == "readable"
+
+    with pytest.raises(TypeError, match="requires a class"):
+        f.register("decimal.Decimal", lambda o: "decimal", strict=True)
+
+
+def test_observers():
+    import json
+
+    @singledispatch
+    def f(o: Any) -> str:
+        return "object"
+
+    registered = []
+    unregistered = []
+
+    @f.on_register
+    def on_register(cls, func):
+        # Observers run without the dispatcher's lock held.
+        registered.append((cls, func, f(cls())))
+
+    f.on_unregister(lambda cls, func: unregistered.append((cls, func)))
+
+    _f_int = f.register(int, lambda o: "int")
+    f.register(Union[str, bytes], lambda o: "text")
+    f.register_before(float, lambda o: None)
+    assert registered[0] == (int, _f_int, "int")
+    assert [cls for cls, _, _ in registered] == [int, str, bytes]
+
+    f.unregister(int)
+    assert unregistered == [(int, _f_int)]
+    assert f(1) == "object"
+
+    # Lazy handlers are shown as they are in the registry.
+    assert f.register(dict, "json:dumps") == "json:dumps"
+    assert registered[-1] == (dict, "json:dumps", "{}")
+    f.unregister(dict)
+    assert unregistered[-1] == (dict, json.dumps)
+
+    f.remove_observer(on_register)
+    f.register(int, _f_int)
+    assert len(registered) == 4
+    with pytest.raises(ValueError, match="not an observer"):
+        f.remove_observer(on_register)
+
+
+def test_remove_bound_method_observer():
+    @singledispatch
+    def f(o: Any) -> str:
+        return "object"
+
+    class Recorder:
+        def __init__(self):
+            self.registered = []
+
+        def record(self, cls, func):
+            self.registered.append(cls)
+
+    recorder = Recorder()
+    f.on_register(recorder.record)
+    f.register(int, lambda o: "int")
+    f.remove_observer(recorder.record)
+    f.register(str, lambda o: "str")
+    assert recorder.registered == [int]
+
+
+def test_unregister_invalid():
+    @singledispatch
+    def f(o: Any) -> str:
+        return "object"
+
+    with pytest.raises(KeyError):
+        f.unregister(int)
+    with pytest.raises(TypeError, match="cannot be unregistered"):
+        f.unregister(object)
+
+    unregistered = []
+    f.on_unregister(lambda cls, func: unregistered.append(cls))
+    f.register(int, lambda o: "int")
+    assert f(1) == "int"
+    with pytest.raises(TypeError, match="cannot be unregistered"):
+        f.unregister(Union[int, object])
+    assert int in f.registry
+    assert f(1) == "int"
+    assert unregistered == []
+    f.unregister(int)
+
+    g = f.derive()
+    f.register(int, lambda o: "int")
+    with pytest.raises(KeyError):
+        g.unregister(int)
+
+
+def test_on_missing():
+    missing = []
+
+    def on_missing(cls):
+        missing.append(cls)
+        if cls is int:
+            f.register(int, lambda o: "int")
+        elif cls is float:
+            return lambda o: "float"
+
+    def _f(o: Any) -> str:
+        return "object"
+
+    f = singledispatch(_f, on_missing=on_missing)
+
+    assert f(1) == "int"
+    assert f(2) == "int"
+    assert f(1.0) == "float"
+    assert f("a") == "object"
+    assert f("b") == "object"
+    assert f(object()) == "object"
+    assert missing == [int, float, str]
+
+    assert f.derive().dispatch(bytes) is _f
+    assert missing == [int, float, str, bytes]
+
+
+def _install_plugin(path, name, module, entry_points):
+    (path / f"{module}.py").write_text(
+        "def handle_fraction(o):\n    return 'fraction'\n\n"
+        "def handle_complex(o):\n    return 'complex'\n"
+    )
+    dist_info = path / f"{name}-1.0.dist-info"
+    dist_info.mkdir()
+    (dist_info / "METADATA").write_text(
+        f"Metadata-Version: 2.1\nName: {name}\nVersion: 1.0\n"
+    )
+    (dist_info / "entry_points.txt").write_text(
+        f"[singledispatch_native_{module}]\n"
+        + "".join(f"{type_name} = {module}:{handler}\n" for type_name, handler in entry_points)
+    )
+
+
+def test_load_entry_points(tmp_path, monkeypatch):
+    from fractions import Fraction
+
+    _install_plugin(
+        tmp_path,
+        "plugin_a",
+        "sdn_plugin_eager",
+        [("fractions.Fraction", "handle_fraction"), ("decimal.Decimal", "handle_fraction")],
+    )
+    _install_plugin(
+        tmp_path,
+        "plugin_b",
+        "sdn_plugin_eager",
+        [("decimal.Decimal", "handle_complex"), ("builtins.complex", "handle_complex [extra]")],
+    )
+    monkeypatch.syspath_prepend(tmp_path)
+
+    @singledispatch
+    def f(o: Any) -> str:
+        return "object"
+
+    with pytest.warns(RuntimeWarning, match="Conflicting entry points .* for 'decimal.Decimal'"):
+        f.load_entry_points("singledispatch_native_sdn_plugin_eager")
+
+    assert f.registry["fractions.Fraction"] == "sdn_plugin_eager:handle_fraction"
+    assert "decimal.Decimal" not in f.registry
+    assert f(Fraction(1, 2)) == "fraction"
+    assert f(1j) == "complex"
+    assert f(1) == "object"
+
+
+def test_load_entry_points_lazy(tmp_path, monkeypatch):
+    from fractions import Fraction
+
+    _install_plugin(
+        tmp_path,
+        "plugin_lazy",
+        "sdn_plugin_lazy",
+        [("fractions.Fraction", "handle_fraction"), ("Fraction", "handle_fraction")],
+    )
+    monkeypatch.syspath_prepend(tmp_path)
+
+    @singledispatch
+    def f(o: Any) -> str:
+        return "object"
+
+    f.load_entry_points("singledispatch_native_sdn_plugin_lazy", lazy=True)
+    assert f.registry.keys() == {object}
+
+    with pytest.warns(RuntimeWarning, match="Invalid entry point 'Fraction = "):
+        assert f(Fraction(1, 2)) == "fraction"
+    assert f(1) == "object"
+
+
+def test_load_entry_points_lazy_derived(tmp_path, monkeypatch):
+    from fractions import Fraction
+
+    _install_plugin(
+        tmp_path, "plugin_derived", "sdn_plugin_derived", [("fractions.Fraction", "handle_fraction")]
+    )
+    monkeypatch.syspath_prepend(tmp_path)
+
+    @singledispatch
+    def f(o: Any) -> str:
+        return "object"
+
+    f.load_entry_points("singledispatch_native_sdn_plugin_derived", lazy=True)
+    g = f.derive()
+    assert g(Fraction(1, 2)) == "fraction"
+    assert g(1) == "object"
+    assert f(Fraction(1, 2)) == "fraction"
+
+
+def test_load_entry_points_not_a_class(tmp_path, monkeypatch):
+    from fractions import Fraction
+
+    _install_plugin(tmp_path, "plugin_bad", "sdn_plugin_bad", [("sys.path", "handle_fraction")])
+    _install_plugin(
+        tmp_path, "plugin_good", "sdn_plugin_good", [("fractions.Fraction", "handle_fraction")]
+    )
+    monkeypatch.syspath_prepend(tmp_path)
+
+    @singledispatch
+    def f(o: Any) -> str:
+        return "object"
+
+    f.load_entry_points("singledispatch_native_sdn_plugin_bad")
+    f.load_entry_points("singledispatch_native_sdn_plugin_good")
+    with pytest.warns(
+        RuntimeWarning,
+        match="Invalid entry point 'sys.path = sdn_plugin_bad:handle_fraction' .* does not name a class",
+    ):
+        assert f(1) == "object"
+    assert f(Fraction(1, 2)) == "fraction"
+    assert "sys.path" not in f.registry
+
+
+def test_fall_through():
+    def _f(o: Any) -> Any:
+        return NotImplemented if o is None or o == -100 else "object"
+
+    f = singledispatch(_f, fall_through=True)
+
+    @f.register(int)
+    def _f_int(o: int) -> Any:
+        return "int" if o >= 0 else NotImplemented
+
+    @f.register(numbers.Integral)
+    def _f_integral(o: numbers.Integral) -> Any:
+        return "integral" if o > -10 else NotImplemented
+
+    assert f(1) == "int"
+    assert f(-1) == "integral"
+    assert f(-10) == "object"
+    assert f(1.0) == "object"
+    assert f.dispatch(int) is f.dispatch(int)
+
+    with pytest.raises(TypeError, match="NoneType'> returned NotImplemented: <function .*_f at"):
+        f(None)
+    with pytest.raises(TypeError, match="_f_int at .*_f_integral at .*_f at"):
+        f(-100)
+
+    g = singledispatch(_f)
+    g.register(int, _f_int)
+    assert g(-1) is NotImplemented
+
+
+@singledispatch
+def exact_fun(o: Any) -> str:
+    return "object"
+
+
+@exact_fun.register(int, exact=True)
+def _exact_fun_int(o: int) -> str:
+    return "exactly int"
+
+
+exact_fun.register(numbers.Integral, lambda o: "integral")
+
+
+@pytest.mark.parametrize(
+    "v,ret",
+    [
+        (1, "exactly int"),
+        (True, "integral"),
+        (1.0, "object"),
+    ]
+)
+def test_exact(v, ret):
+    assert exact_fun(v) == ret
+
+
+def test_exact_next_handler_and_override():
+    assert exact_fun.next_handler(int, _exact_fun_int)(1) == "integral"
+    assert exact_fun.dispatch(bool)(True) == "integral"
+
+    with exact_fun.override(int, lambda o: "overridden"):
+        assert exact_fun(1) == "overridden"
+        assert exact_fun(True) == "overridden"
+
+
+def test_exact_unregister():
+    @singledispatch
+    def f(o: Any) -> str:
+        return "object"
+
+    f.register(int, lambda o: "int")
+    f.register(int, exact=True)(lambda o: "exactly int")
+    assert f(1) == "exactly int"
+    assert f(True) == "int"
+
+    f.unregister(int, exact=True)
+    assert f(1) == "int"
+
+    with pytest.raises(TypeError, match="exact handlers must be registered for classes"):
+        f.register(list[int], lambda o: "list", exact=True)
+
+
+class Model:
+    pass
+
+
+class JsonMixin:
+    pass
+
+
+class User(Model, JsonMixin):
+    pass
+
+
+def test_custom_mro():
+    calls = []
+
+    def mixins_first(cls, types):
+        calls.append(cls)
+        assert set(types) == {object, Model, JsonMixin}
+        mro = [typ for typ in cls.__mro__ if typ in types]
+        return sorted(mro, key=lambda typ: not typ.__name__.endswith("Mixin"))
+
+    def _f(o: Any) -> str:
+        return "object"
+
+    f = singledispatch(_f, mro=mixins_first)
+    f.register(Model, lambda o: "model")
+    f.register(JsonMixin, lambda o: "json")
+
+    assert f(User()) == "json"
+    assert f(User()) == "json"
+    assert f(Model()) == "model"
+    assert calls == [User, Model]
+
+    assert f.derive()(User()) == "json"
+
+    g = singledispatch(_f)
+    g.register(Model, lambda o: "model")
+    g.register(JsonMixin, lambda o: "json")
+    assert g(User()) == "model"
+
+
+def test_custom_mro_uses_dispatcher():
+    def registered_first(cls, types):
+        # The most recently registered type first, including one being registered.
+        registered = list(f.registry)
+        return sorted(
+            (typ for typ in cls.__mro__ if typ in types),
+            key=lambda typ: registered.index(typ) if typ in registered else len(registered),
+            reverse=True,
+        )
+
+    def _f(o: Any) -> str:
+        return "object"
+
+    f = singledispatch(_f, mro=registered_first)
+    f.register(JsonMixin, lambda o: "json")
+    f.register(Model, lambda o: "model", strict=True)
+
+    assert f(User()) == "model"
+    assert f.check() == []
+
+
+def test_ordered_resolution():
+    def _f(o: Any) -> str:
+        return "object"
+
+    f = singledispatch(_f, resolution="ordered")
+    _f_integral = f.register(numbers.Integral, lambda o: "integral")
+    _f_int = f.register(int, lambda o: "int")
+    _f_bool = f.register(bool, lambda o: "bool")
+
+    assert f(True) == "integral"
+    assert f(1) == "integral"
+    assert f(1.0) == "object"
+    assert list(f.registry) == [object, numbers.Integral, int, bool]
+    assert f.explain(bool) == [
+        (numbers.Integral, _f_integral),
+        (int, _f_int),
+        (bool, _f_bool),
+        (object, _f),
+    ]
+
+    with pytest.raises(ValueError, match="invalid resolution 'specific'"):
+        singledispatch(_f, resolution="specific")
+    with pytest.raises(ValueError, match="cannot be combined"):
+        singledispatch(_f, resolution="ordered", mro=lambda cls, types: types)
+
+
+def test_explain():
+    assert some_fun.explain(bool) == [
+        (int, some_fun.registry[int]),
+        (object, some_fun.registry[object]),
+    ]
+    assert exact_fun.explain(int)[:2] == [
+        (int, _exact_fun_int),
+        (numbers.Integral, exact_fun.registry[numbers.Integral]),
+    ]
+
+
+@pytest.mark.parametrize("reverse", [False, True])
+def test_deterministic_ambiguity(reverse):
+    f, Readable, Writable, Stream = _ambiguous_abcs()
+    first, second = (Writable, Readable) if reverse else (Readable, Writable)
+    f.register(first, lambda o: "first")
+    f.register(second, lambda o: "second")
+
+    for _ in range(3):
+        assert f.check() == [(Stream, first, second)]
+        with pytest.raises(RuntimeError, match=f"Ambiguous dispatch: {first} or {second}"):
+            f.dispatch(Stream)
+
+
+class BrokenSubclassCheck(Exception):
+    pass
+
+
+class BrokenMeta(type):
+    def __subclasscheck__(cls, subclass):
+        raise BrokenSubclassCheck(subclass)
+
+    def __subclasses__(cls):
+        return iter(type.__subclasses__(cls))
+
+
+class Broken(metaclass=BrokenMeta):
+    pass
+
+
+def test_misbehaving_metaclass():
+    def _f(o: Any) -> str:
+        return "object"
+
+    f = singledispatch(_f)
+    f.register(Broken, lambda o: "broken")
+    assert f(Broken()) == "broken"
+    with pytest.raises(BrokenSubclassCheck):
+        f(1)
+    with pytest.raises(BrokenSubclassCheck):
+        f.dispatch(str)
+    with pytest.raises(BrokenSubclassCheck):
+        f.check(classes=[int])
+
+
+class ClassProxy:
+    """Stands in for a class, like the class proxies of some C extensions."""
+
+    def __init__(self, cls, mro=tuple):
+        self.__wrapped__ = cls
+        self.__mro__ = mro(cls.__mro__)
+
+    def __getattr__(self, name):
+        return getattr(self.__wrapped__, name)
+
+
+class BasesOnly:
+    """Supports `issubclass()` through `__bases__`, but has no `__mro__`."""
+
+    __bases__ = (int,)
+
+
+@pytest.mark.parametrize(
+    "cls,ret",
+    [
+        (ClassProxy(bool), "It's an int!"),
+        (ClassProxy(bool, mro=list), "It's an int!"),
+        (ClassProxy(str, mro=list), "It's a string!"),
+        (BasesOnly(), "It's an int!"),
+    ],
+)
+def test_class_like_objects(cls, ret):
+    assert some_fun.dispatch(cls)(None) == ret
+
+
+class InstanceProxy:
+    """Reports the class of the object it wraps as its own, like `unittest.mock`."""
+
+    def __init__(self, wrapped):
+        self.wrapped = wrapped
+
+    @property
+    def __class__(self):
+        return type(self.wrapped)
+
+
+class InstanceProxyChild(InstanceProxy):
+    pass
+
+
+class ReportsInt:
+    def __getattribute__(self, name):
+        return int if name == "__class__" else super().__getattribute__(name)
+
+
+def test_dispatch_on_reported_class():
+    f = singledispatch(lambda o: "object")
+    f.register(int, lambda o: "int")
+
+    for _ in range(2):
+        # The classes are dispatched on themselves first, which must not make
+        # calls with their instances skip looking up `__class__`.
+        for cls in (InstanceProxy, InstanceProxyChild, ReportsInt):
+            assert f.dispatch(cls)(None) == "object"
+        assert f(InstanceProxy(1)) == "int"
+        assert f(InstanceProxy("a")) == "object"
+        assert f(InstanceProxyChild(1)) == "int"
+        assert f(ReportsInt()) == "int"
+        assert f(1) == "int"
+
+
+def test_typing_union():
+    f = singledispatch(lambda o: "object")
+    f.register(Union[int, str], lambda o: "int or str")
+    assert f(1) == "int or str"
+    assert f("a") == "int or str"
+    assert f(1.0) == "object"
+
+
+@pytest.mark.skipif(sys.version_info < (3, 10), reason="requires types.UnionType")
+def test_union_type():
+    f = singledispatch(lambda o: "object")
+    f.register(int | str, lambda o: "int or str")
+    assert f(1) == "int or str"
+    assert f("a") == "int or str"
+    assert f(1.0) == "object"
+
+
+@pytest.mark.skipif(sys.version_info < (3, 12), reason="requires typing.TypeAliasType")
+def test_type_alias_type():
+    from typing import TypeAliasType
+
+    Number = TypeAliasType("Number", int | float)
+    Integers = TypeAliasType("Integers", list[int])
+    Nested = TypeAliasType("Nested", Number)
+
+    f = singledispatch(lambda o: "object", validate_generics=True)
+    f.register(Nested, lambda o: "number")
+    f.register(Integers, lambda o: "integers")
+    assert f(1) == "number"
+    assert f(1.0) == "number"
+    assert f([1]) == "integers"
+    assert list(f.registry) == [object, int, float, list[int]]
+    with pytest.raises(TypeError, match="does not match registered type"):
+        f(["a"])
+
+
+@pytest.mark.skipif(sys.version_info < (3, 11), reason="requires typing.get_overloads")
+def test_register_overloads():
+    from typing import overload
+
+    f = singledispatch(lambda o: "object")
+
+    @overload
+    def _f(o: int) -> str: ...
+
+    @overload
+    def _f(o: Union[list[int], str], *args) -> str: ...
+
+    @f.register
+    def _f(o, *args):
+        return "overloaded"
+
+    assert f(1) == "overloaded"
+    assert f([1]) == "overloaded"
+    assert f("a") == "overloaded"
+    assert f(1.0) == "object"
+    assert list(f.registry) == [object, int, list[int], str]
+
+    @overload
+    def _g(o: float) -> str: ...
+
+    @overload
+    def _g(o: 1) -> str: ...
+
+    def _g(o):
+        return "invalid"
+
+    with pytest.raises(TypeError, match="Invalid overload of .*: 1 is not a class"):
+        f.register(_g)
+    assert f(1.0) == "object"
+
+    @overload
+    def _k(o) -> str: ...
+
+    def _k(o):
+        return "unannotated"
+
+    with pytest.raises(TypeError, match="its first parameter is not annotated"):
+        f.register(_k)
+
+    def _h(o: int):
+        return "not overloaded"
+
+    with pytest.raises(TypeError, match="must be a class or union type"):
+        f.register(_h)
+
+
+def _cycle_through_handler(f, handler):
+    f.register(int, handler)
+    f(1)
+
+
+def _cycle_through_combination(f, handler):
+    f.register_before(int, handler)
+    f.register_around(int, lambda next_method, o: next_method(o))
+    f(1)
+
+
+def _cycle_through_partial_registration(f, handler):
+    handler.registration = f.register(int)
+    handler.registration(handler)
+
+
+def _cycle_through_override(f, handler):
+    handler.override = f.override(int, handler)
+
+    def _run():
+        with handler.override:
+            assert f(1) is f
+            assert f.derive()(1) is f
+        handler.override.__enter__()
+
+    contextvars.copy_context().run(_run)
+
+
+def _cycle_through_lazy_handler(f, handler):
+    module = types.ModuleType("sdn_gc_lazy_handler")
+    module.handle = handler
+    sys.modules[module.__name__] = module
+    try:
+        f.register(int, "sdn_gc_lazy_handler:handle")
+        assert f(1) is f
+    finally:
+        del sys.modules[module.__name__]
+
+
+def _cycle_through_derived(f, handler):
+    handler.derived = f.derive()
+    handler.derived.register(str, handler)
+    handler.derived("a")
+
+
+def _cycle_through_observer(f, handler):
+    f.on_register(handler)
+
+
+@pytest.mark.parametrize(
+    "make_cycle",
+    [
+        _cycle_through_handler,
+        _cycle_through_combination,
+        _cycle_through_partial_registration,
+        _cycle_through_override,
+        _cycle_through_lazy_handler,
+        _cycle_through_derived,
+        _cycle_through_observer,
+    ],
+)
+def test_gc_collects_cycles(make_cycle):
+    def _make():
+        def handler(*args):
+            return f
+
+        f = singledispatch(lambda o: "object")
+        make_cycle(f, handler)
+        return weakref.ref(handler)
+
+    ref = _make()
+    gc.collect()
+    assert ref() is None
+
+
+def test_update_wrapper():
+    import functools
+
+    def _f(o: Any) -> str:
+        """Describe an object."""
+        return "object"
+
+    f = functools.update_wrapper(singledispatch(_f), _f)
+    assert f.__wrapped__ is _f
+    assert f.__name__ == "_f"
+    assert f.__qualname__ == _f.__qualname__
+    assert f.__doc__ == "Describe an object."
+    assert f.__module__ == __name__
+    assert f.__annotations__ == _f.__annotations__
+    assert f(1) == "object"
+    assert singledispatch(_f).__doc__ != f.__doc__
+
+
+def test_weakref_and_attributes():
+    f = singledispatch(lambda o: "object")
+    f.custom = "attribute"
+    assert f.custom == "attribute"
+    assert f.__dict__ == {"custom": "attribute"}
+    assert f.derive().__dict__ == {}
+    del f.custom
+    with pytest.raises(AttributeError, match="no attribute 'custom'"):
+        f.custom
+    with pytest.raises(AttributeError, match="'registry' is read-only"):
+        f.registry = {}
+    with pytest.raises(AttributeError, match="'register' is read-only"):
+        f.register = None
+
+    with pytest.raises(AttributeError, match="'__class__' is read-only"):
+        f.__class__ = int
+
+    ref = weakref.ref(f)
+    assert ref() is f
+    registration_ref = weakref.ref(f.register(int))
+    del f
+    gc.collect()
+    assert ref() is None
+    assert registration_ref() is None
+
+
+def test_gc_collects_cycles_through_attributes():
+    def _make():
+        f = singledispatch(lambda o: "object")
+        f.self = f
+        return weakref.ref(f)
+
+    ref = _make()
+    gc.collect()
+    assert ref() is None
+
+
+def test_call_arguments():
+    f = singledispatch(lambda *args, **kwargs: (args, kwargs))
+    assert f(1, 2, key=3) == ((1, 2), {"key": 3})
+    with pytest.raises(TypeError, match="requires at least 1 positional argument"):
+        f()
+    with pytest.raises(TypeError, match="requires at least 1 positional argument"):
+        f(key=3)
+
+
+def test_call_conventions():
+    import functools
+
+    def fail(*args):
+        raise ValueError(args)
+
+    f = singledispatch(lambda *args, **kwargs: (args, kwargs))
+    f.register(str, fail)
+    assert f(*(1, 2), **{"key": 3}) == ((1, 2), {"key": 3})
+    assert functools.partial(f, 1)(2, key=3) == ((1, 2), {"key": 3})
+    assert list(map(f, [1, 2])) == [((1,), {}), ((2,), {})]
+    assert f.derive()(1, key=2) == ((1,), {"key": 2})
+    with pytest.raises(ValueError, match="'a', 1"):
+        f("a", 1)
+
+
+class IntegersMeta(type):
+    def __subclasscheck__(cls, subclass):
+        return issubclass(subclass, int)
+
+
+class Integers(metaclass=IntegersMeta):
+    pass
+
+
+def test_large_registry():
+    def _f(o: Any) -> str:
+        return "object"
+
+    f = singledispatch(_f)
+    for i in range(500):
+        f.register(type(f"Registered{i}", (Leaf,), {}), lambda o: "registered")
+    f.register(Integers, lambda o: "integers")
+    f.register(collections.abc.Sized, lambda o: "sized")
+    f.register(Base, lambda o: "base")
+    g = f.derive()
+    g.register(collections.abc.Sequence, lambda o: "sequence")
+
+    for d in (f, g):
+        assert d(True) == "integers"
+        assert d({}) == "sized"
+        assert d(Leaf()) == "base"
+    assert f([]) == "sized"
+    assert g([]) == "sequence"